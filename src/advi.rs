@@ -0,0 +1,212 @@
+//! Automatic differentiation variational inference, ADVI (Kucukelbir et al. 2017).
+//!
+//! The posterior of `u = (log s, logit tau, mu, gamma)` is approximated by the normal
+//! `N(mean, L L^T)` that maximises the elbo. As in [`crate::map`] and [`crate::laplace`], the
+//! target is the posterior of `hmc::U`, which is [`crate::mh::log_posterior`], including the
+//! log jacobian of the transform, so the elbo is a lower bound of the evidence of `laplace`.
+
+use crate::data::Data;
+use crate::linalg::{self, Matrix};
+use crate::map;
+use crate::mh::Parameters;
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::Normal;
+
+// step size sequence of Kucukelbir et al. (2017):
+// rho_k = ETA * k^(-1/2 + 1e-16) / (1 + sqrt(s_k)), s_k = ALPHA g_k^2 + (1 - ALPHA) s_{k-1}
+static ETA: f64 = 0.1;
+static ALPHA: f64 = 0.1;
+// the elbo is estimated with N_ELBO draws every EVAL_EVERY iterations, and the optimisation
+// stops when the mean or the median of the relative changes of the estimates drops below TOL_REL_OBJ
+static EVAL_EVERY: usize = 100;
+static N_ELBO: usize = 100;
+static TOL_REL_OBJ: f64 = 0.01;
+// number of relative changes kept for the convergence check
+static CB_SIZE: usize = 10;
+
+/// The family of the normal approximation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// Independent coordinates, a diagonal L.
+    MeanField,
+    /// Any covariance, a lower triangular L.
+    FullRank,
+}
+
+/// The fitted approximation `N(mean, chol chol^T)` in the unconstrained space.
+pub struct Output {
+    pub family: Family,
+    pub mean: Vec<f64>,
+    pub chol: Matrix,
+    /// The iteration and the estimate of the elbo at every evaluation.
+    pub elbo_trace: Vec<(usize, f64)>,
+    pub n_iter: usize,
+    pub converged: bool,
+}
+
+/// Stochastic gradient ascent on the elbo from `start`, with `n_grad` draws for every gradient estimate.
+pub fn run(data: &Data, family: Family, start: &[f64], n_grad: usize, max_iter: usize, seed: u64) -> Output {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let d = start.len();
+
+    let log_p = |u: &[f64]| -> (f64, Vec<f64>) {
+        let (v, g) = map::neg_log_posterior(data, u, true);
+        (-v, g.iter().map(|x| -x).collect())
+    };
+
+    let mut mean = start.to_vec();
+    // for the mean field family only the diagonal of L is used, and it is stored as log sd (omega)
+    let mut omega = vec![0.0; d];
+    let mut chol = linalg::identity(d);
+
+    // running averages of the squared gradients, for the step size sequence
+    let mut s_mean = vec![0.0; d];
+    let mut s_scale = vec![vec![0.0; d]; d];
+
+    let mut elbo_trace: Vec<(usize, f64)> = Vec::new();
+    let mut rel_changes: Vec<f64> = Vec::new();
+    let mut converged = false;
+    let mut n_iter = 0;
+
+    for k in 1..=max_iter {
+        n_iter = k;
+
+        let scale = current_chol(family, &omega, &chol);
+
+        let mut g_mean = vec![0.0; d];
+        let mut g_scale = vec![vec![0.0; d]; d];
+
+        // reparameterisation: u = mean + L eta, eta ~ N(0, I)
+        for _ in 0..n_grad {
+            let eta: Vec<f64> = (0..d).map(|_| normal.sample(&mut rng)).collect();
+            let u: Vec<f64> = linalg::mat_vec(&scale, &eta).iter().zip(mean.iter()).map(|(a, b)| a + b).collect();
+            let (_, g) = log_p(&u);
+
+            for i in 0..d {
+                g_mean[i] += g[i] / n_grad as f64;
+                match family {
+                    Family::MeanField => g_scale[i][i] += g[i] * eta[i] * omega[i].exp() / n_grad as f64,
+                    Family::FullRank => {
+                        for j in 0..=i {
+                            g_scale[i][j] += g[i] * eta[j] / n_grad as f64;
+                        }
+                    }
+                }
+            }
+        }
+
+        // gradient of the entropy: 1 for every omega_i, 1/L_ii for the full rank family
+        for i in 0..d {
+            g_scale[i][i] += match family {
+                Family::MeanField => 1.0,
+                Family::FullRank => 1.0 / chol[i][i],
+            };
+        }
+
+        let decay = (k as f64).powf(-0.5 + 1e-16);
+        let a = if k == 1 { 1.0 } else { ALPHA };
+
+        for i in 0..d {
+            s_mean[i] = a * g_mean[i].powi(2) + (1.0 - a) * s_mean[i];
+            mean[i] += ETA * decay / (1.0 + s_mean[i].sqrt()) * g_mean[i];
+
+            match family {
+                Family::MeanField => {
+                    s_scale[i][i] = a * g_scale[i][i].powi(2) + (1.0 - a) * s_scale[i][i];
+                    omega[i] += ETA * decay / (1.0 + s_scale[i][i].sqrt()) * g_scale[i][i];
+                }
+                Family::FullRank => {
+                    for j in 0..=i {
+                        s_scale[i][j] = a * g_scale[i][j].powi(2) + (1.0 - a) * s_scale[i][j];
+                        chol[i][j] += ETA * decay / (1.0 + s_scale[i][j].sqrt()) * g_scale[i][j];
+                    }
+                }
+            }
+        }
+
+        if k % EVAL_EVERY == 0 {
+            let elbo = elbo(&mut rng, &log_p, &mean, &current_chol(family, &omega, &chol));
+
+            if let Some(&(_, prev)) = elbo_trace.last() {
+                rel_changes.push(((elbo - prev) / elbo).abs());
+                if rel_changes.len() > CB_SIZE {
+                    rel_changes.remove(0);
+                }
+            }
+            elbo_trace.push((k, elbo));
+
+            if !rel_changes.is_empty() {
+                let mean_change = rel_changes.iter().sum::<f64>() / rel_changes.len() as f64;
+                if mean_change < TOL_REL_OBJ || median(&rel_changes) < TOL_REL_OBJ {
+                    converged = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    Output {
+        family,
+        chol: current_chol(family, &omega, &chol),
+        mean,
+        elbo_trace,
+        n_iter,
+        converged,
+    }
+}
+
+fn current_chol(family: Family, omega: &[f64], chol: &Matrix) -> Matrix {
+    match family {
+        Family::MeanField => {
+            let mut l = linalg::identity(omega.len());
+            for (i, w) in omega.iter().enumerate() {
+                l[i][i] = w.exp();
+            }
+            l
+        }
+        Family::FullRank => chol.clone(),
+    }
+}
+
+// monte carlo estimate of E[log p(u)] + entropy of N(mean, L L^T)
+fn elbo<F: Fn(&[f64]) -> (f64, Vec<f64>)>(rng: &mut StdRng, log_p: &F, mean: &[f64], chol: &Matrix) -> f64 {
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let d = mean.len();
+
+    let expected: f64 = (0..N_ELBO)
+        .map(|_| {
+            let eta: Vec<f64> = (0..d).map(|_| normal.sample(rng)).collect();
+            let u: Vec<f64> = linalg::mat_vec(chol, &eta).iter().zip(mean.iter()).map(|(a, b)| a + b).collect();
+            log_p(&u).0
+        })
+        .sum::<f64>() / N_ELBO as f64;
+
+    let entropy = 0.5 * d as f64 * (1.0 + std::f64::consts::TAU.ln())
+        + (0..d).map(|i| chol[i][i].abs().ln()).sum::<f64>();
+
+    expected + entropy
+}
+
+fn median(x: &[f64]) -> f64 {
+    let mut v = x.to_vec();
+    v.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    v[v.len() / 2]
+}
+
+impl Output {
+    /// `n` draws from the fitted approximation, mapped back to the original parameters.
+    pub fn sample(&self, n: usize, seed: u64) -> Vec<Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let normal = Normal::new(0.0, 1.0).unwrap();
+
+        (0..n)
+            .map(|_| {
+                let z: Vec<f64> = (0..self.mean.len()).map(|_| normal.sample(&mut rng)).collect();
+                let u: Vec<f64> = linalg::mat_vec(&self.chol, &z).iter().zip(self.mean.iter()).map(|(a, b)| a + b).collect();
+                Parameters::from_unconstrained(&u).0
+            })
+            .collect()
+    }
+}
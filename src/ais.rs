@@ -0,0 +1,70 @@
+//! Annealed importance sampling (Neal 2001) of the evidence.
+//!
+//! Chains are drawn from the importance proposal q and annealed along the geometric path
+//! `q^(1-beta) * p^beta` to p = exp([`crate::mh::log_posterior`]), with the random walk moves of
+//! [`crate::smc`]; the log evidence is the log of the normalising constant of p, as in `smc`.
+
+use crate::data::Data;
+use crate::smc::{self, Particle};
+use rand::prelude::*;
+
+// exponent of the annealing schedule beta_k = (k/K)^SCHEDULE_POWER, which
+// spends more of the temperatures close to the proposal where the path changes fastest
+static SCHEDULE_POWER: i32 = 4;
+
+/// The evidence, averaged over independent runs.
+pub struct Output {
+    /// Mean of the log evidence of the runs.
+    pub log_evidence: f64,
+    pub std_error: f64,
+    pub run_log_evidence: Vec<f64>,
+    /// Effective sample size of the importance weights of every run.
+    pub ess: Vec<f64>,
+}
+
+/// `n_runs` independent AIS runs with `n_chains` annealed chains each; every chain
+/// goes through `n_temps` intermediate distributions with `n_mcmc` MH moves at each of them.
+pub fn run(data: Data, n_runs: usize, n_chains: usize, n_temps: usize, n_mcmc: usize, seed: u64) -> Output {
+    assert!(n_runs >= 2, "need at least two runs for a standard error");
+    assert!(n_temps >= 1, "need at least one step from the proposal to the posterior");
+
+    let target = smc::posterior(&data);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let betas: Vec<f64> = (0..=n_temps)
+        .map(|k| (k as f64 / n_temps as f64).powi(SCHEDULE_POWER))
+        .collect();
+
+    let mut run_log_evidence = Vec::with_capacity(n_runs);
+    let mut ess = Vec::with_capacity(n_runs);
+
+    for _ in 0..n_runs {
+        let mut chains: Vec<Particle> = (0..n_chains).map(|_| target.sample(&mut rng)).collect();
+        let mut log_w = vec![0.0; n_chains];
+
+        for b in betas.windows(2) {
+            for (lw, c) in log_w.iter_mut().zip(chains.iter()) {
+                *lw += (b[1] - b[0]) * c.log_l();
+            }
+            smc::rejuvenate(&mut rng, &target, &mut chains, &log_w, b[1], n_mcmc);
+        }
+
+        // Z = mean of the importance weights of the annealed chains
+        let log_z = smc::log_sum_exp(&log_w) - (n_chains as f64).ln();
+        let w: Vec<f64> = log_w.iter().map(|lw| (lw - log_z).exp() / n_chains as f64).collect();
+
+        run_log_evidence.push(log_z);
+        ess.push(1.0 / w.iter().map(|wi| wi * wi).sum::<f64>());
+    }
+
+    let n = n_runs as f64;
+    let log_evidence = run_log_evidence.iter().sum::<f64>() / n;
+    let var = run_log_evidence.iter().map(|z| (z - log_evidence).powi(2)).sum::<f64>() / (n - 1.0);
+
+    Output {
+        log_evidence,
+        std_error: (var / n).sqrt(),
+        run_log_evidence,
+        ess,
+    }
+}
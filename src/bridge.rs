@@ -0,0 +1,156 @@
+//! Bridge sampling (Meng & Wong 1996) of the evidence from posterior draws.
+//!
+//! Works with the draws of any of the samplers. The draws are split in half: the first half
+//! fits a normal proposal in the unconstrained space of [`Parameters::to_unconstrained`], and the
+//! second half enters the iterative estimator together with as many draws of the proposal.
+
+use crate::{diagnostics, linalg};
+use crate::mh::Parameters;
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::Normal;
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+
+static TOLERANCE: f64 = 1e-10;
+static MAX_ITER: usize = 1000;
+
+/// The evidence and its approximate error.
+pub struct Output {
+    pub log_marginal_likelihood: f64,
+    /// Approximate relative mean squared error of the evidence (Fruhwirth-Schnatter 2004).
+    pub relative_mse: f64,
+    /// Iterations of the estimator until convergence.
+    pub n_iter: usize,
+}
+
+/// Bridge sampling estimate of the normalising constant of `log_posterior` from its draws `samples`, at least four.
+pub fn run<F: Fn(&Parameters) -> f64>(samples: &[Parameters], log_posterior: F, seed: u64) -> Result<Output> {
+    if samples.len() < 4 {
+        bail!("bridge sampling needs at least four posterior draws, got {}", samples.len());
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let u: Vec<Vec<f64>> = samples.iter().map(|p| p.to_unconstrained()).collect();
+    let (fit, iter) = u.split_at(u.len() / 2);
+
+    let (mean, cov) = linalg::mean_cov(fit);
+    let l = linalg::cholesky(&cov)
+        .ok_or_else(|| eyre!("the covariance of the posterior draws is not positive definite, e.g. because the chain did not move"))?;
+
+    // posterior density in the unconstrained space
+    let log_target = |x: &[f64]| -> f64 {
+        let (p, log_jacobian) = Parameters::from_unconstrained(x);
+        log_posterior(&p) + log_jacobian
+    };
+
+    let n1 = iter.len();
+    let n2 = n1;
+    let normal = Normal::new(0.0, 1.0).unwrap();
+
+    let generated: Vec<Vec<f64>> = (0..n2)
+        .map(|_| {
+            let z: Vec<f64> = (0..mean.len()).map(|_| normal.sample(&mut rng)).collect();
+            linalg::mat_vec(&l, &z).iter().zip(mean.iter()).map(|(a, b)| a + b).collect()
+        })
+        .collect();
+
+    // log p and log g at the posterior draws (q11, q12) and at the proposal draws (q21, q22)
+    let q11: Vec<f64> = iter.iter().map(|x| log_target(x)).collect();
+    let q12: Vec<f64> = iter.iter().map(|x| linalg::mvn_log_density(x, &mean, &l)).collect();
+    let q21: Vec<f64> = generated.iter().map(|x| log_target(x)).collect();
+    let q22: Vec<f64> = generated.iter().map(|x| linalg::mvn_log_density(x, &mean, &l)).collect();
+
+    let l1: Vec<f64> = q11.iter().zip(q12.iter()).map(|(a, b)| a - b).collect();
+    let l2: Vec<f64> = q21.iter().zip(q22.iter()).map(|(a, b)| a - b).collect();
+
+    let s1 = n1 as f64 / (n1 + n2) as f64;
+    let s2 = n2 as f64 / (n1 + n2) as f64;
+
+    // work with ratios relative to the median of l1 to avoid overflow
+    let lstar = median(&l1).ok_or_else(|| eyre!("the posterior density is not finite at any of the posterior draws"))?;
+
+    let mut r = 1.0;
+    let mut n_iter = 0;
+    loop {
+        let numer: f64 = l2.iter()
+            .map(|l| { let e = (l - lstar).exp(); e / (s1 * e + s2 * r) })
+            .sum::<f64>() / n2 as f64;
+        let denom: f64 = l1.iter()
+            .map(|l| 1.0 / (s1 * (l - lstar).exp() + s2 * r))
+            .sum::<f64>() / n1 as f64;
+
+        let r_new = numer / denom;
+        n_iter += 1;
+
+        let converged = ((r_new - r) / r_new).abs() < TOLERANCE;
+        r = r_new;
+
+        if converged || n_iter >= MAX_ITER {
+            break;
+        }
+    }
+
+    let log_marginal_likelihood = r.ln() + lstar;
+
+    Ok(Output {
+        log_marginal_likelihood,
+        relative_mse: relative_mse(&q11, &q12, &q21, &q22, log_marginal_likelihood),
+        n_iter,
+    })
+}
+
+// approximate relative mean squared error of the estimate (Fruhwirth-Schnatter 2004),
+// the posterior draws are autocorrelated, so their term is inflated by the integrated autocorrelation time
+fn relative_mse(q11: &[f64], q12: &[f64], q21: &[f64], q22: &[f64], log_ml: f64) -> f64 {
+    let n1 = q11.len() as f64;
+    let n2 = q21.len() as f64;
+    let s1 = n1 / (n1 + n2);
+    let s2 = n2 / (n1 + n2);
+
+    // f1 = p/(s1 p + s2 g) at the proposal draws, f2 = g/(s1 p + s2 g) at the posterior draws,
+    // with p the normalised posterior
+    let f1: Vec<f64> = q21.iter().zip(q22.iter())
+        .map(|(lp, lg)| 1.0 / (s1 + s2 * (lg - lp + log_ml).exp()))
+        .collect();
+    let f2: Vec<f64> = q11.iter().zip(q12.iter())
+        .map(|(lp, lg)| 1.0 / (s1 * (lp - log_ml - lg).exp() + s2))
+        .collect();
+
+    let (m1, v1) = mean_var(&f1);
+    let (m2, v2) = mean_var(&f2);
+
+    v1 / m1.powi(2) / n2 + diagnostics::integrated_autocorrelation_time(&f2) * v2 / m2.powi(2) / n1
+}
+
+// median of the finite values, None if there are none
+fn median(x: &[f64]) -> Option<f64> {
+    let mut v: Vec<f64> = x.iter().cloned().filter(|a| a.is_finite()).collect();
+    v.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    v.get(v.len() / 2).copied()
+}
+
+fn mean_var(x: &[f64]) -> (f64, f64) {
+    let n = x.len() as f64;
+    let m = x.iter().sum::<f64>() / n;
+    let v = x.iter().map(|a| (a - m).powi(2)).sum::<f64>() / (n - 1.0);
+    (m, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::importance;
+    use crate::smc::tests::{toy, LOG_Z};
+
+    #[test]
+    fn recovers_the_evidence_of_a_gaussian_target() {
+        let p = toy();
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples: Vec<Parameters> = (0..4000).map(|_| Parameters::from_slice(&p.sample(&mut rng).to_vec())).collect();
+
+        let out = run(&samples, |q| LOG_Z + p.log_density(&importance::Parameters::from_slice(&q.to_vec())), 2).unwrap();
+        assert!((out.log_marginal_likelihood - LOG_Z).abs() < 0.05, "log evidence {}", out.log_marginal_likelihood);
+    }
+}
@@ -0,0 +1,290 @@
+//! Periodic checkpoints of a running chain and resuming from them.
+//!
+//! A checkpoint holds everything the remaining iterations depend on: the state of the
+//! chain and the state of the random number generator. The draws are not part of it:
+//! they are appended to an output file as they are drawn, and the checkpoint only records
+//! the length of that file, which is flushed at every checkpoint. A resumed run truncates
+//! the file to that length and appends the remaining draws, so that the file ends up with
+//! exactly the same draws as an uninterrupted [`run`] with the same seed.
+
+use crate::data::Data;
+use crate::diagnostics::Draw;
+use crate::sink::{self, Sink};
+use crate::{mh, trace};
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A chain that can be checkpointed.
+pub trait Resumable {
+    /// Everything the next steps depend on, apart from the data and the random number generator.
+    type State: Serialize + DeserializeOwned;
+    /// What is recorded after every step of the sampling phase.
+    type Draw: Draw;
+
+    /// Name stored in the checkpoint, so that it is not resumed by another sampler.
+    const NAME: &'static str;
+
+    fn data(&self) -> &Data;
+    fn step(&mut self, rng: &mut ChaCha12Rng);
+    fn state(&self) -> Self::State;
+    fn restore(&mut self, state: &Self::State);
+    fn draw(&self) -> Self::Draw;
+
+    /// Log of the density the chain samples, up to a constant, at its current draw:
+    /// [`mh::log_posterior`] unless the sampler targets another one.
+    fn log_density(&self) -> f64 {
+        mh::log_posterior(self.data(), &mh::Parameters::from_slice(&self.draw().values()))
+    }
+}
+
+/// Where the draws of a checkpointed chain go: every `thin`-th draw after the burn-in is
+/// appended to the file at `path`, in the given format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Output {
+    pub path: String,
+    pub format: sink::Format,
+    pub header: trace::Header,
+    pub thin: usize,
+    /// Length of the file at the last checkpoint, in bytes.
+    pub len: u64,
+}
+
+/// Contents of a checkpoint file.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint<S> {
+    pub sampler: String,
+    pub n_rows: usize,
+    pub data_checksum: u64,
+    pub n_burnin: usize,
+    pub n_samples: usize,
+    pub seed: u64,
+    /// Iterations done so far, burn-in included.
+    pub iteration: usize,
+    pub state: S,
+    pub rng: ChaCha12Rng,
+    pub output: Output,
+}
+
+// only the name of the sampler, the progress and the output, to pick the chain type before reading the rest
+#[derive(Deserialize)]
+struct Header {
+    sampler: String,
+    n_burnin: usize,
+    n_samples: usize,
+    iteration: usize,
+    output: Output,
+}
+
+/// Writes the same draws as `sink::stream(chain, n_burnin, n_samples, output.thin, seed, ..)` to
+/// `output.path`, writing a checkpoint to `path` every `every` iterations and at the end.
+pub fn run<C: Resumable>(chain: &mut C, n_burnin: usize, n_samples: usize, seed: u64, path: &str, every: usize, output: Output) -> Result<()> {
+    let file_sink = sink::create_with(&output.path, output.format, &output.header, usize::MAX)?;
+    let checkpoint = Checkpoint {
+        sampler: C::NAME.to_string(),
+        n_rows: chain.data().len(),
+        data_checksum: checksum(chain.data()),
+        n_burnin,
+        n_samples,
+        seed,
+        iteration: 0,
+        state: chain.state(),
+        rng: ChaCha12Rng::seed_from_u64(seed),
+        output,
+    };
+    continue_run(chain, checkpoint, file_sink, path, every)
+}
+
+/// Restores `chain` from the checkpoint at `path` and runs it to the end, checkpointing
+/// to the same file and appending to the same output. The chain must have been built on the same data.
+pub fn resume<C: Resumable>(chain: &mut C, path: &str, every: usize) -> Result<()> {
+    let text = std::fs::read_to_string(path).wrap_err_with(|| format!("cannot read the checkpoint '{}'", path))?;
+    let checkpoint: Checkpoint<C::State> = serde_json::from_str(&text)
+        .wrap_err_with(|| format!("invalid checkpoint '{}'", path))?;
+
+    if checkpoint.sampler != C::NAME {
+        bail!("the checkpoint '{}' is of the {} sampler, not of {}", path, checkpoint.sampler, C::NAME);
+    }
+    if checkpoint.n_rows != chain.data().len() || checkpoint.data_checksum != checksum(chain.data()) {
+        bail!("the checkpoint '{}' was written for different data", path);
+    }
+
+    let output = &checkpoint.output;
+    let file_sink = sink::reopen(&output.path, output.format, &output.header, output.len, usize::MAX)?;
+
+    chain.restore(&checkpoint.state);
+    continue_run(chain, checkpoint, file_sink, path, every)
+}
+
+/// Name of the sampler that wrote the checkpoint at `path`.
+pub fn sampler_name(path: &str) -> Result<String> {
+    Ok(header(path)?.sampler)
+}
+
+/// Iterations done by the chain in the checkpoint at `path`, and in total, burn-in included.
+pub fn progress(path: &str) -> Result<(usize, usize)> {
+    let header = header(path)?;
+    Ok((header.iteration, header.n_burnin + header.n_samples))
+}
+
+/// Where the chain in the checkpoint at `path` writes its draws.
+pub fn output(path: &str) -> Result<Output> {
+    Ok(header(path)?.output)
+}
+
+fn header(path: &str) -> Result<Header> {
+    let text = std::fs::read_to_string(path).wrap_err_with(|| format!("cannot read the checkpoint '{}'", path))?;
+    serde_json::from_str(&text).wrap_err_with(|| format!("invalid checkpoint '{}'", path))
+}
+
+fn continue_run<C: Resumable>(chain: &mut C, mut checkpoint: Checkpoint<C::State>, mut file_sink: Box<dyn Sink>, path: &str, every: usize) -> Result<()> {
+    let total = checkpoint.n_burnin + checkpoint.n_samples;
+
+    while checkpoint.iteration < total {
+        chain.step(&mut checkpoint.rng);
+        checkpoint.iteration += 1;
+
+        // same thinning as sink::stream: the first draw after the burn-in and every thin-th after it
+        if checkpoint.iteration > checkpoint.n_burnin && (checkpoint.iteration - checkpoint.n_burnin - 1).is_multiple_of(checkpoint.output.thin.max(1)) {
+            file_sink.write(&chain.draw().values())?;
+        }
+
+        if checkpoint.iteration.is_multiple_of(every.max(1)) || checkpoint.iteration == total {
+            file_sink.flush()?;
+            checkpoint.output.len = std::fs::metadata(&checkpoint.output.path)
+                .wrap_err_with(|| format!("cannot read the output file '{}'", checkpoint.output.path))?
+                .len();
+            checkpoint.state = chain.state();
+            save(&checkpoint, path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// written to a temporary file first, so that a crash while writing keeps the previous checkpoint
+fn save<S: Serialize>(checkpoint: &Checkpoint<S>, path: &str) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string(checkpoint)?).wrap_err_with(|| format!("cannot write the checkpoint '{}'", tmp))?;
+    std::fs::rename(&tmp, path).wrap_err_with(|| format!("cannot write the checkpoint '{}'", path))?;
+    Ok(())
+}
+
+// FNV-1a over the groups and the bits of the values
+fn checksum(data: &Data) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for r in data.iter() {
+        let bytes = std::iter::once(r.group as u64).chain(r.x.iter().map(|x| x.to_bits()));
+        for b in bytes.flat_map(|v| v.to_le_bytes()) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{self, Row};
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn data() -> Data {
+        (0..40).map(|i| Row { group: (i % 4 + 1) as u8, x: vec![(i as f64 * 0.37).sin(), (i as f64 * 0.91).cos()] }).collect()
+    }
+
+    fn file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("mcmc_project_checkpoint_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    fn output(path: &str, format: sink::Format) -> Output {
+        Output { path: path.to_string(), format, header: trace::Header::new(&data::parameter_names(2)), thin: 3, len: 0 }
+    }
+
+    // an MH chain that panics after `steps` steps, like a run that is killed
+    struct Crashing {
+        chain: mh::Chain,
+        steps: usize,
+    }
+
+    impl Resumable for Crashing {
+        type State = <mh::Chain as Resumable>::State;
+        type Draw = mh::Parameters;
+
+        const NAME: &'static str = mh::Chain::NAME;
+
+        fn data(&self) -> &Data {
+            self.chain.data()
+        }
+
+        fn step(&mut self, rng: &mut ChaCha12Rng) {
+            if self.steps == 0 {
+                panic!("the run was interrupted");
+            }
+            self.steps -= 1;
+            Resumable::step(&mut self.chain, rng)
+        }
+
+        fn state(&self) -> Self::State {
+            self.chain.state()
+        }
+
+        fn restore(&mut self, state: &Self::State) {
+            self.chain.restore(state)
+        }
+
+        fn draw(&self) -> mh::Parameters {
+            self.chain.draw()
+        }
+    }
+
+    #[test]
+    fn an_uninterrupted_run_writes_the_streamed_draws() {
+        let streamed = file("streamed.csv");
+        let mut file_sink = sink::create(&streamed, sink::Format::Csv, &data::parameter_names(2), usize::MAX).unwrap();
+        sink::stream(&mut mh::Chain::new(data()), 50, 200, 3, 7, &mut file_sink).unwrap();
+
+        let checkpointed = file("checkpointed.csv");
+        run(&mut mh::Chain::new(data()), 50, 200, 7, &file("checkpointed.json"), 30, output(&checkpointed, sink::Format::Csv)).unwrap();
+
+        assert_eq!(std::fs::read(&streamed).unwrap(), std::fs::read(&checkpointed).unwrap());
+    }
+
+    #[test]
+    fn a_resumed_run_writes_the_same_draws_as_an_uninterrupted_one() {
+        for format in [sink::Format::Csv, sink::Format::Jsonl, sink::Format::Binary] {
+            let full = file(&format!("full.{}", format.extension()));
+            run(&mut mh::Chain::new(data()), 50, 200, 7, &file("full.json"), 30, output(&full, format)).unwrap();
+
+            // interrupted between the checkpoints at iterations 120 and 150, after draws past the last one were written
+            let resumed = file(&format!("resumed.{}", format.extension()));
+            let checkpoint = file(&format!("resumed_{}.json", format.extension()));
+            let mut crashing = Crashing { chain: mh::Chain::new(data()), steps: 145 };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                run(&mut crashing, 50, 200, 7, &checkpoint, 30, output(&resumed, format))
+            }));
+            assert!(result.is_err());
+
+            // the thinning comes from the checkpoint
+            resume(&mut mh::Chain::new(data()), &checkpoint, 30).unwrap();
+
+            assert_eq!(std::fs::read(&full).unwrap(), std::fs::read(&resumed).unwrap(), "{:?}", format);
+            assert_eq!(sink::load(&resumed, format, &data::parameter_names(2)).unwrap().len(), 67);
+        }
+    }
+
+    #[test]
+    fn a_checkpoint_is_not_resumed_on_other_data() {
+        let checkpoint = file("other_data.json");
+        run(&mut mh::Chain::new(data()), 5, 10, 7, &checkpoint, 5, output(&file("other_data.csv"), sink::Format::Csv)).unwrap();
+
+        let mut other = data();
+        other[0].x[0] += 1.0;
+        assert!(resume(&mut mh::Chain::new(other), &checkpoint, 5).is_err());
+    }
+}
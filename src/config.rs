@@ -0,0 +1,268 @@
+//! The configuration of a run, read from and written to TOML or JSON files.
+//!
+//! Every entry has a default, so a configuration file only needs the entries it changes;
+//! the command-line options override the file.
+
+use crate::data::{self, Data};
+use crate::{hmc, importance, mh, multichain, sink};
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Everything a run depends on; missing entries of a configuration file keep their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data: String,
+    pub format: data::Format,
+    pub output: String,
+    /// The draws of the single chains are streamed to the output in this format, flushed every `flush_every` draws.
+    pub output_format: sink::Format,
+    pub flush_every: usize,
+    /// The summaries need every draw, so with them the memory of a single chain grows with its length;
+    /// without them it is constant (a checkpointed chain is read back from its output for the summaries).
+    pub summary: bool,
+    /// Chain id, iteration, log posterior, log-likelihood and sampler statistics before the parameters of every draw.
+    pub metadata: bool,
+    pub seed: u64,
+    pub burnin: usize,
+    pub samples: usize,
+    pub thin: usize,
+    /// One chain for the single samplers and four for `all` when not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chains: Option<usize>,
+    /// Single chains are checkpointed to this file every `checkpoint_every` iterations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<String>,
+    pub checkpoint_every: usize,
+    pub mh: MhConfig,
+    pub hmc: HmcConfig,
+    pub gibbs: GibbsConfig,
+    pub importance: ImportanceConfig,
+}
+
+/// The `[mh]` section: the starting point and the proposal standard deviations.
+///
+/// Starting points, here and in the other sections, are `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`
+/// for D traits; without one, the chain starts at the posterior mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MhConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<Vec<f64>>,
+    pub s_proposal_sd: f64,
+    pub mean_proposal_sd: f64,
+}
+
+/// The `[hmc]` section: the starting point and the tuning of [`hmc::Tuning`].
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HmcConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<Vec<f64>>,
+    pub L: usize,
+    pub dt: f64,
+    /// The diagonal of the inverse mass matrix, one entry per parameter, unit masses when not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub m: Option<Vec<f64>>,
+}
+
+/// The `[gibbs]` section: the starting point.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GibbsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<Vec<f64>>,
+}
+
+/// The `[importance]` section: the number of draws and the proposal.
+///
+/// The proposal means are `(mu1, ..., muD, gamma1, ..., gammaD)`, see [`importance::Proposal::for_data`] when not given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportanceConfig {
+    pub samples: usize,
+    pub s_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean: Option<Vec<f64>>,
+    pub sd: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data: "data.csv".to_string(),
+            format: data::Format::default(),
+            output: ".".to_string(),
+            output_format: sink::Format::default(),
+            flush_every: 1000,
+            summary: true,
+            metadata: false,
+            seed: 42,
+            burnin: 1000,
+            samples: 8000,
+            thin: 1,
+            chains: None,
+            checkpoint: None,
+            checkpoint_every: 1000,
+            mh: MhConfig::default(),
+            hmc: HmcConfig::default(),
+            gibbs: GibbsConfig::default(),
+            importance: ImportanceConfig::default(),
+        }
+    }
+}
+
+impl Default for MhConfig {
+    fn default() -> Self {
+        let tuning = mh::Tuning::default();
+        Self { start: None, s_proposal_sd: tuning.s_proposal_sd, mean_proposal_sd: tuning.mean_proposal_sd }
+    }
+}
+
+impl Default for HmcConfig {
+    fn default() -> Self {
+        let tuning = hmc::Tuning::default();
+        Self { start: None, L: tuning.L, dt: tuning.dt, m: tuning.m }
+    }
+}
+
+impl Default for ImportanceConfig {
+    fn default() -> Self {
+        Self { samples: 10000, s_rate: importance::S_RATE, mean: None, sd: importance::PROPOSAL_SD }
+    }
+}
+
+impl Config {
+    /// Reads a configuration file; the format follows the extension, .toml or .json.
+    pub fn load(path: &str) -> Result<Config> {
+        let text = std::fs::read_to_string(path).wrap_err_with(|| format!("cannot read the configuration file '{}'", path))?;
+
+        let config: Config = match extension(path).as_str() {
+            "toml" => toml::from_str(&text).wrap_err_with(|| format!("invalid configuration file '{}'", path))?,
+            "json" => serde_json::from_str(&text).wrap_err_with(|| format!("invalid configuration file '{}'", path))?,
+            other => bail!("unknown configuration format '{}', expected .toml or .json", other),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Writes the configuration, in the format of the extension as in [`Config::load`].
+    pub fn save(&self, path: &str) -> Result<()> {
+        let text = match extension(path).as_str() {
+            "json" => serde_json::to_string_pretty(self)?,
+            _ => toml::to_string_pretty(self)?,
+        };
+        std::fs::write(path, text).wrap_err_with(|| format!("cannot write the configuration to '{}'", path))
+    }
+
+    /// Checks that the sizes, step sizes and proposal scales are positive and the starting points inside the support.
+    pub fn validate(&self) -> Result<()> {
+        let positive = [
+            ("samples", self.samples as f64),
+            ("thin", self.thin as f64),
+            ("checkpoint_every", self.checkpoint_every as f64),
+            ("flush_every", self.flush_every as f64),
+            ("mh.s_proposal_sd", self.mh.s_proposal_sd),
+            ("mh.mean_proposal_sd", self.mh.mean_proposal_sd),
+            ("hmc.L", self.hmc.L as f64),
+            ("hmc.dt", self.hmc.dt),
+            ("importance.samples", self.importance.samples as f64),
+            ("importance.s_rate", self.importance.s_rate),
+            ("importance.sd", self.importance.sd),
+        ];
+        for (name, value) in positive {
+            if value.is_nan() || value <= 0.0 {
+                bail!("{} must be positive, got {}", name, value);
+            }
+        }
+        if self.metadata && self.output_format == sink::Format::Stan {
+            bail!("Stan CSV has its own diagnostic columns, the metadata can't be added to it");
+        }
+        if let Some(m) = &self.hmc.m {
+            if m.iter().any(|m| m.is_nan() || *m <= 0.0) {
+                bail!("the HMC masses must be positive, got {:?}", m);
+            }
+        }
+        for (name, start) in [("mh", &self.mh.start), ("hmc", &self.hmc.start), ("gibbs", &self.gibbs.start)] {
+            if let Some(q) = start {
+                if q.len() < 4 || q.len() % 2 != 0 {
+                    bail!("{}.start needs s, tau and at least one mu and gamma, got {:?}", name, q);
+                }
+                if q[0] <= 0.0 || q[1] <= 0.0 || q[1] >= 1.0 {
+                    bail!("{}.start needs s > 0 and 0 < tau < 1, got {:?}", name, q);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the lengths of the starting points, the masses and the proposal means, which depend on
+    /// the number of traits of the data.
+    pub fn validate_traits(&self, n_traits: usize) -> Result<()> {
+        let n = 2 + 2 * n_traits;
+        let vectors = [
+            ("mh.start", self.mh.start.as_ref(), n),
+            ("hmc.start", self.hmc.start.as_ref(), n),
+            ("gibbs.start", self.gibbs.start.as_ref(), n),
+            ("hmc.m", self.hmc.m.as_ref(), n),
+            ("importance.mean", self.importance.mean.as_ref(), 2 * n_traits),
+        ];
+        for (name, vector, len) in vectors {
+            if let Some(v) = vector {
+                if v.len() != len {
+                    bail!("{} has {} values, the data has {} traits so it needs {}", name, v.len(), n_traits, len);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of chains, one when not given.
+    pub fn n_chains(&self) -> usize {
+        self.chains.unwrap_or(1)
+    }
+
+    /// The proposal standard deviations of [`mh::Chain`].
+    pub fn mh_tuning(&self) -> mh::Tuning {
+        mh::Tuning { s_proposal_sd: self.mh.s_proposal_sd, mean_proposal_sd: self.mh.mean_proposal_sd }
+    }
+
+    /// The leapfrog steps, step size and masses of [`hmc::Chain`].
+    pub fn hmc_tuning(&self) -> hmc::Tuning {
+        hmc::Tuning { L: self.hmc.L, dt: self.hmc.dt, m: self.hmc.m.clone() }
+    }
+
+    /// The importance proposal, with the means of [`importance::Proposal::for_data`] when not given.
+    pub fn proposal(&self, data: &Data) -> importance::Proposal {
+        let mean = self.importance.mean.clone().unwrap_or_else(|| importance::Proposal::for_data(data).mean);
+        importance::Proposal { s_rate: self.importance.s_rate, mean, sd: self.importance.sd }
+    }
+
+    /// The settings of every chain of a [`multichain`] run.
+    pub fn chain_settings(&self) -> multichain::Settings {
+        multichain::Settings {
+            n_burnin: self.burnin,
+            n_samples: self.samples,
+            thin: self.thin,
+            mh: self.mh_tuning(),
+            hmc: self.hmc_tuning(),
+        }
+    }
+
+    /// The configured starting point of a sampler, if any.
+    pub fn start(&self, sampler: multichain::Sampler) -> Option<Vec<f64>> {
+        match sampler {
+            multichain::Sampler::MH => self.mh.start.clone(),
+            multichain::Sampler::HMC => self.hmc.start.clone(),
+            multichain::Sampler::Gibbs => self.gibbs.start.clone(),
+        }
+    }
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase()
+}
@@ -1,22 +1,288 @@
-use csv::Reader;
-use serde::Deserialize;
+//! Loading and validating the data set.
+
+use csv::{ReaderBuilder, StringRecord};
+use serde::{Deserialize, Serialize};
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
+use std::io::Read;
+
+/// Number of groups of the mixing model; groups are numbered from 1.
+pub const N_GROUPS: u8 = 4;
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+/// One observation: the group (1 to 4) and the trait values, the same number in every row.
+#[derive(Deserialize, Debug, Clone)]
 pub struct Row {
     pub group: u8,
-    pub x1: f64,
-    pub x2: f64
+    pub x: Vec<f64>,
 }
 
+/// The rows of the data set, in file order.
 pub type Data = Vec<Row>;
 
-pub fn load_data() -> Result<Data> {
+/// Names of the columns and the field delimiter of the CSV input.
+///
+/// Without `traits`, every column other than the group is a trait, in the order of the header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Format {
+    pub group: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub traits: Vec<String>,
+    pub delimiter: char,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self { group: "group".to_string(), traits: Vec::new(), delimiter: ',' }
+    }
+}
+
+/// Number of traits of every row.
+pub fn n_traits(data: &Data) -> usize {
+    data.first().map_or(0, |r| r.x.len())
+}
+
+/// Names of the parameters with `n_traits` traits, in the order of the draws:
+/// `s`, `tau`, `mu1` to `muD` and `gamma1` to `gammaD`.
+pub fn parameter_names(n_traits: usize) -> Vec<String> {
+    let mut names = vec!["s".to_string(), "tau".to_string()];
+    names.extend((1..=n_traits).map(|k| format!("mu{}", k)));
+    names.extend((1..=n_traits).map(|k| format!("gamma{}", k)));
+    names
+}
+
+/// Number of traits of a draw of `n_parameters` values, the inverse of `parameter_names(..).len()`.
+pub fn n_traits_of(n_parameters: usize) -> usize {
+    n_parameters.saturating_sub(2) / 2
+}
+
+/// Mean of the traits of the rows in `group`, zero for an empty group.
+pub fn group_mean(data: &Data, group: u8) -> Vec<f64> {
+    let rows: Vec<&Row> = data.iter().filter(|r| r.group == group).collect();
+    let n = rows.len().max(1) as f64;
+    (0..n_traits(data)).map(|k| rows.iter().map(|r| r.x[k]).sum::<f64>() / n).collect()
+}
+
+/// Reads a comma separated file with a `group` column and one column per trait, e.g. `x1` and `x2`.
+pub fn load_data(path: &str) -> Result<Data> {
+    load_from_path(path, &Format::default())
+}
+
+/// Reads and validates a CSV file in the given format.
+pub fn load_from_path(path: &str, format: &Format) -> Result<Data> {
+    let file = std::fs::File::open(path).wrap_err_with(|| format!("cannot open the data file '{}'", path))?;
+    load_from_reader(file, format).wrap_err_with(|| format!("invalid data file '{}'", path))
+}
+
+/// Reads and validates CSV data in the given format from any reader.
+///
+/// Rows are numbered as lines of the input, so the header is row 1. Every row with more
+/// fields than the header, an unknown group or a missing, unparsable or non-finite value is
+/// reported, and every group must have at least one row.
+pub fn load_from_reader<R: Read>(reader: R, format: &Format) -> Result<Data> {
+    if !format.delimiter.is_ascii() {
+        bail!("the delimiter must be an ASCII character, got '{}'", format.delimiter);
+    }
+
+    // flexible, so that a short row is reported with the others instead of stopping the read
+    let mut rdr = ReaderBuilder::new()
+        .delimiter(format.delimiter as u8)
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(reader);
+
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| -> Result<usize> {
+        headers.iter().position(|h| h == name)
+            .ok_or_else(|| eyre!("no column '{}', the columns are {:?}", name, headers.iter().collect::<Vec<&str>>()))
+    };
+    let traits: Vec<String> = match format.traits.is_empty() {
+        true => headers.iter().filter(|h| *h != format.group).map(|h| h.to_string()).collect(),
+        false => format.traits.clone(),
+    };
+    if traits.is_empty() {
+        bail!("no trait columns besides '{}'", format.group);
+    }
+    let mut columns = vec![column(&format.group)?];
+    for name in traits.iter() {
+        columns.push(column(name)?);
+    }
+    let names: Vec<&str> = std::iter::once(format.group.as_str()).chain(traits.iter().map(|t| t.as_str())).collect();
+
     let mut data = Vec::new();
-    let mut rdr = Reader::from_path("data.csv")?;
-    for result in rdr.deserialize() {
-        let row: Row = result?;
-        data.push(row);
+    let mut errors = Vec::new();
+
+    for result in rdr.records() {
+        let record = result?;
+        let line = record.position().map_or(0, |p| p.line());
+
+        if record.len() > headers.len() {
+            errors.push(format!("row {}: {} fields, the header has {}", line, record.len(), headers.len()));
+            continue;
+        }
+        match parse_row(&record, &columns, &names) {
+            Ok(row) => data.push(row),
+            Err(e) => errors.push(format!("row {}: {}", line, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("{} invalid row(s):\n{}", errors.len(), errors.join("\n"));
     }
+
+    validate(&data)?;
     Ok(data)
-}
\ No newline at end of file
+}
+
+/// Checks that every group of the model has at least one row.
+pub fn validate(data: &Data) -> Result<()> {
+    let empty: Vec<u8> = (1..=N_GROUPS).filter(|g| !data.iter().any(|r| r.group == *g)).collect();
+    if !empty.is_empty() {
+        bail!("no rows in group(s) {:?}, every group from 1 to {} needs at least one", empty, N_GROUPS);
+    }
+    Ok(())
+}
+
+// columns[0] and names[0] are the group, the others the traits
+fn parse_row(record: &StringRecord, columns: &[usize], names: &[&str]) -> Result<Row> {
+    let field = |i: usize, name: &str| -> Result<&str> {
+        match record.get(columns[i]) {
+            Some(v) if !v.is_empty() => Ok(v),
+            _ => Err(eyre!("missing value of {}", name)),
+        }
+    };
+
+    let group_field = field(0, names[0])?;
+    let group: u8 = group_field.parse()
+        .map_err(|_| eyre!("{} '{}' is not a group number", names[0], group_field))?;
+    if group == 0 || group > N_GROUPS {
+        bail!("unknown group {}, expected 1 to {}", group, N_GROUPS);
+    }
+
+    let value = |i: usize, name: &str| -> Result<f64> {
+        let v = field(i, name)?;
+        let x: f64 = v.parse().map_err(|_| eyre!("{} '{}' is not a number", name, v))?;
+        if !x.is_finite() {
+            bail!("{} is {}", name, x);
+        }
+        Ok(x)
+    };
+
+    let x = (1..columns.len()).map(|i| value(i, names[i])).collect::<Result<Vec<f64>>>()?;
+    Ok(Row { group, x })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one row per group, so that `validate` passes
+    static HEADER_AND_GROUPS: &str = "group,x1,x2\n1,0.1,0.2\n2,0.3,0.4\n3,0.5,0.6\n4,0.7,0.8\n";
+
+    fn load(csv: &str) -> Result<Data> {
+        load_from_reader(csv.as_bytes(), &Format::default())
+    }
+
+    fn error(csv: &str) -> String {
+        format!("{:#}", load(csv).unwrap_err())
+    }
+
+    #[test]
+    fn reads_every_trait_column() {
+        let data = load(HEADER_AND_GROUPS).unwrap();
+        assert_eq!(data.len(), 4);
+        assert_eq!(n_traits(&data), 2);
+        assert_eq!(data[3].group, 4);
+        assert_eq!(data[3].x, vec![0.7, 0.8]);
+    }
+
+    #[test]
+    fn reads_the_configured_columns_and_delimiter() {
+        let csv = "b;g;a\n0.2;1;0.1\n0.4;2;0.3\n0.6;3;0.5\n0.8;4;0.7\n";
+        let format = Format { group: "g".to_string(), traits: vec!["a".to_string(), "b".to_string()], delimiter: ';' };
+        let data = load_from_reader(csv.as_bytes(), &format).unwrap();
+        assert_eq!(data[0].x, vec![0.1, 0.2]);
+    }
+
+    #[test]
+    fn rejects_an_unknown_group() {
+        let e = error(&format!("{}5,0.1,0.2\n", HEADER_AND_GROUPS));
+        assert!(e.contains("row 6: unknown group 5"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_group_that_is_not_a_number() {
+        let e = error(&format!("{}a,0.1,0.2\n", HEADER_AND_GROUPS));
+        assert!(e.contains("row 6: group 'a' is not a group number"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_an_empty_field() {
+        let e = error(&format!("{}1,,0.2\n", HEADER_AND_GROUPS));
+        assert!(e.contains("row 6: missing value of x1"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_missing_field() {
+        let e = error(&format!("{}1,0.1\n2,abc,0.2\n3\n", HEADER_AND_GROUPS));
+        assert!(e.contains("3 invalid row(s)"), "{}", e);
+        assert!(e.contains("row 6: missing value of x2"), "{}", e);
+        assert!(e.contains("row 7: x1 'abc' is not a number"), "{}", e);
+        assert!(e.contains("row 8: missing value of x1"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_an_extra_field() {
+        let e = error(&format!("{}1,0.1,0.2,0.3\n", HEADER_AND_GROUPS));
+        assert!(e.contains("row 6: 4 fields, the header has 3"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_value_that_is_not_a_number() {
+        let e = error(&format!("{}1,0.1,abc\n", HEADER_AND_GROUPS));
+        assert!(e.contains("row 6: x2 'abc' is not a number"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_non_finite_values() {
+        for value in ["inf", "-inf", "NaN"] {
+            let e = error(&format!("{}2,{},0.2\n", HEADER_AND_GROUPS, value));
+            assert!(e.contains("row 6: x1 is"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_row() {
+        let e = error(&format!("{}5,0.1,0.2\n1,0.1,abc\n", HEADER_AND_GROUPS));
+        assert!(e.starts_with("2 invalid row(s):\nrow 6: "), "{}", e);
+        assert!(e.contains("\nrow 7: "), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_missing_column() {
+        let format = Format { traits: vec!["x1".to_string(), "x3".to_string()], ..Format::default() };
+        let e = format!("{:#}", load_from_reader(HEADER_AND_GROUPS.as_bytes(), &format).unwrap_err());
+        assert!(e.contains("no column 'x3'"), "{}", e);
+
+        let e = error("grp,x1\n1,0.1\n");
+        assert!(e.contains("no column 'group'"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_file_without_traits() {
+        let e = error("group\n1\n2\n3\n4\n");
+        assert!(e.contains("no trait columns"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_an_empty_group() {
+        let e = error("group,x1\n1,0.1\n2,0.2\n4,0.4\n");
+        assert!(e.contains("no rows in group(s) [3]"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_a_non_ascii_delimiter() {
+        let format = Format { delimiter: 'é', ..Format::default() };
+        assert!(load_from_reader(HEADER_AND_GROUPS.as_bytes(), &format).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Differential evolution Markov chain Monte Carlo, DE-MC (ter Braak 2006), with snooker updates (ter Braak & Vrugt 2008).
+//!
+//! Every chain proposes a jump along the difference of two other chains, so the proposals
+//! adapt to the scale and correlations of [`crate::mh::log_posterior`]. As in [`crate::ensemble`],
+//! the draws of the chains are returned interleaved, ordered by iteration and then by chain.
+
+use crate::data::{self, Data};
+use crate::mh::{self, Parameters};
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::{Uniform, Normal};
+
+// sd of the small jitter added to every differential-evolution jump
+static JITTER: f64 = 1e-4;
+// every JUMP_EVERY-th generation uses gamma = 1, which lets chains jump between modes
+static JUMP_EVERY: usize = 10;
+
+/// A population of chains updated one after the other.
+pub struct Chain {
+    data: Data,
+    // number of parameters, 2 + 2D
+    n_dim: usize,
+    chains: Vec<Vec<f64>>,
+    log_p: Vec<f64>,
+    p_snooker: f64,
+    de_accepted: usize,
+    de_proposed: usize,
+    snooker_accepted: usize,
+    snooker_proposed: usize,
+}
+
+impl Chain {
+    /// A population of `n_chains` chains, at least three, or four with snooker updates.
+    /// `p_snooker` is the probability of making a snooker update instead of a
+    /// parallel direction (DE) update; 0 gives plain DE-MC.
+    pub fn new(data: Data, n_chains: usize, p_snooker: f64) -> Self {
+        assert!(n_chains >= 3, "DE-MC needs at least three chains");
+        assert!(p_snooker == 0.0 || n_chains >= 4, "snooker updates need at least four chains");
+
+        let n_dim = 2 + 2 * data::n_traits(&data);
+
+        Self {
+            data,
+            n_dim,
+            chains: vec![vec![0.0; n_dim]; n_chains],
+            log_p: vec![f64::NEG_INFINITY; n_chains],
+            p_snooker,
+            de_accepted: 0,
+            de_proposed: 0,
+            snooker_accepted: 0,
+            snooker_proposed: 0,
+        }
+    }
+
+    /// Runs `n_burnin` generations, then returns `n_samples` draws from every chain, ordered by iteration and then by chain.
+    pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.init_chains(&mut rng);
+
+        for g in 0..n_burnin {
+            self.step(&mut rng, g);
+        }
+
+        self.de_accepted = 0;
+        self.de_proposed = 0;
+        self.snooker_accepted = 0;
+        self.snooker_proposed = 0;
+
+        let mut samples = Vec::with_capacity(n_samples * self.chains.len());
+
+        for g in 0..n_samples {
+            self.step(&mut rng, n_burnin + g);
+            for c in self.chains.iter() {
+                samples.push(Parameters::from_slice(c));
+            }
+        }
+
+        samples
+    }
+
+    /// Number of chains.
+    pub fn n_chains(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// Fraction of accepted DE updates since the burn-in.
+    ///
+    /// This rate and [`Chain::snooker_acceptance_rate`] are zero when no update of that kind
+    /// was proposed, e.g. with `p_snooker` 0 or 1.
+    pub fn de_acceptance_rate(&self) -> f64 {
+        self.de_accepted as f64 / self.de_proposed.max(1) as f64
+    }
+
+    /// Fraction of accepted snooker updates since the burn-in.
+    pub fn snooker_acceptance_rate(&self) -> f64 {
+        self.snooker_accepted as f64 / self.snooker_proposed.max(1) as f64
+    }
+
+    // chains start dispersed over the region where the posterior mass is expected
+    fn init_chains(&mut self, rng: &mut StdRng) {
+        let means = Normal::new(0.0, 1.0).unwrap();
+        let unif = Uniform::new(0.0, 1.0).unwrap();
+
+        for i in 0..self.chains.len() {
+            let mut c = vec![0.05 + unif.sample(rng), unif.sample(rng)];
+            c.extend((2..self.n_dim).map(|_| means.sample(rng)));
+            self.log_p[i] = mh::log_posterior(&self.data, &Parameters::from_slice(&c));
+            self.chains[i] = c;
+        }
+    }
+
+    fn step(&mut self, rng: &mut StdRng, generation: usize) {
+        for i in 0..self.chains.len() {
+            if self.p_snooker > 0.0 && rng.gen::<f64>() < self.p_snooker {
+                self.snooker_update(rng, i);
+            } else {
+                self.de_update(rng, i, generation);
+            }
+        }
+    }
+
+    // x* = x_i + gamma * (x_r1 - x_r2) + e, with the usual gamma = 2.38 / sqrt(2d)
+    fn de_update(&mut self, rng: &mut StdRng, i: usize, generation: usize) {
+        let others = self.pick_others(rng, i, 2);
+        let (r1, r2) = (others[0], others[1]);
+
+        let gamma = if (generation + 1).is_multiple_of(JUMP_EVERY) {
+            1.0
+        } else {
+            2.38 / (2.0 * self.n_dim as f64).sqrt()
+        };
+
+        let e = Normal::new(0.0, JITTER).unwrap();
+
+        let proposal: Vec<f64> = (0..self.n_dim)
+            .map(|d| self.chains[i][d] + gamma * (self.chains[r1][d] - self.chains[r2][d]) + e.sample(rng))
+            .collect();
+
+        // the proposal is symmetric => correction factor is 1
+        let new_log_p = mh::log_posterior(&self.data, &Parameters::from_slice(&proposal));
+        let log_ratio = new_log_p - self.log_p[i];
+
+        self.de_proposed += 1;
+        if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+            self.chains[i] = proposal;
+            self.log_p[i] = new_log_p;
+            self.de_accepted += 1;
+        }
+    }
+
+    // snooker update: jump along the line through x_i and another chain z, by the
+    // difference of the projections of two further chains onto that line
+    fn snooker_update(&mut self, rng: &mut StdRng, i: usize) {
+        let others = self.pick_others(rng, i, 3);
+        let (z, r1, r2) = (&self.chains[others[0]], &self.chains[others[1]], &self.chains[others[2]]);
+        let x = &self.chains[i];
+
+        let dir: Vec<f64> = x.iter().zip(z.iter()).map(|(a, b)| a - b).collect();
+        let dir_norm2: f64 = dir.iter().map(|d| d * d).sum();
+
+        if dir_norm2 == 0.0 {
+            return;
+        }
+
+        let project = |y: &Vec<f64>| -> f64 { y.iter().zip(dir.iter()).map(|(a, b)| a * b).sum::<f64>() / dir_norm2 };
+
+        let gamma = Uniform::new(1.2, 2.2).unwrap().sample(rng);
+        let shift = gamma * (project(r1) - project(r2));
+
+        let proposal: Vec<f64> = x.iter().zip(dir.iter()).map(|(a, d)| a + shift * d).collect();
+
+        let dist_new: f64 = proposal.iter().zip(z.iter()).map(|(a, b)| (a - b).powi(2)).sum::<f64>().sqrt();
+        let dist_old = dir_norm2.sqrt();
+
+        let new_log_p = mh::log_posterior(&self.data, &Parameters::from_slice(&proposal));
+        let log_ratio = new_log_p - self.log_p[i] + (self.n_dim as f64 - 1.0) * (dist_new / dist_old).ln();
+
+        self.snooker_proposed += 1;
+        if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+            self.chains[i] = proposal;
+            self.log_p[i] = new_log_p;
+            self.snooker_accepted += 1;
+        }
+    }
+
+    // k distinct chain indices, all different from i
+    fn pick_others(&self, rng: &mut StdRng, i: usize, k: usize) -> Vec<usize> {
+        let mut picked = Vec::with_capacity(k);
+        while picked.len() < k {
+            let j = rng.gen_range(0..self.chains.len());
+            if j != i && !picked.contains(&j) {
+                picked.push(j);
+            }
+        }
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Row;
+
+    // group 4 has the mean of group 1, so that the posterior of tau piles up against its bound at 1
+    fn data() -> Data {
+        (0..40).map(|i| {
+            let m = match i % 4 { 0 | 3 => -1.0, 1 => 1.0, _ => 0.0 };
+            Row { group: (i % 4 + 1) as u8, x: vec![m + 0.3 * (i as f64 * 0.7).sin(), -m + 0.3 * (i as f64 * 1.1).cos()] }
+        }).collect()
+    }
+
+    fn inside(q: &[f64]) -> bool {
+        q[0] > 0.0 && q[0] <= 10.0 && q[1] > 0.0 && q[1] < 1.0
+    }
+
+    #[test]
+    fn de_and_snooker_moves_keep_the_chains_inside_the_support() {
+        let samples = Chain::new(data(), 8, 0.5).run(200, 300, 3);
+        let tau: Vec<f64> = samples.iter().map(|p| p.to_vec()[1]).collect();
+        assert!(samples.iter().all(|p| inside(&p.to_vec())));
+        assert!(tau.iter().cloned().fold(0.0, f64::max) > 0.95);
+    }
+}
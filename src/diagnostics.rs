@@ -0,0 +1,597 @@
+//! Convergence and mixing diagnostics of the draws of any of the samplers.
+//!
+//! Draws of every sampler implement [`Draw`]. The summaries report the mean and 5-95% interval
+//! of every parameter with the bulk and tail ESS and the Monte Carlo standard errors of
+//! Vehtari et al. (2021); the Geweke and Heidelberger-Welch tests check a single chain for
+//! stationarity, and the autocorrelation time suggests how much to thin it.
+
+use crate::{data, gibbs, hmc, importance, mh};
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use statrs::distribution::{Beta, ContinuousCDF, Normal};
+use statrs::function::gamma::gamma;
+
+// window constant of the automatic truncation of the autocorrelation sum (Sokal)
+static ACF_WINDOW: f64 = 5.0;
+
+// fractions of the chain compared by the Geweke test
+static GEWEKE_FIRST: f64 = 0.1;
+static GEWEKE_LAST: f64 = 0.5;
+
+// significance level of the Geweke and Heidelberger-Welch stationarity tests
+static ALPHA: f64 = 0.05;
+
+// the Heidelberger-Welch half-width test passes when the half-width of the 95%
+// interval of the mean is less than this fraction of the mean
+static HALFWIDTH_EPS: f64 = 0.1;
+
+// upper limit of the integral of bessel_k; exp(-x cosh t) is negligible beyond it unless x < 1e-20
+static BESSEL_T_MAX: f64 = 50.0;
+
+/// A single draw of any of the samplers, as `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`.
+pub trait Draw {
+    fn values(&self) -> Vec<f64>;
+}
+
+impl Draw for mh::Parameters {
+    fn values(&self) -> Vec<f64> {
+        self.to_vec()
+    }
+}
+
+impl Draw for hmc::Parameters {
+    fn values(&self) -> Vec<f64> {
+        self.to_vec()
+    }
+}
+
+impl Draw for gibbs::Parameters {
+    fn values(&self) -> Vec<f64> {
+        self.to_vec()
+    }
+}
+
+impl Draw for importance::Parameters {
+    fn values(&self) -> Vec<f64> {
+        self.to_vec()
+    }
+}
+
+/// Mixing of a single parameter: integrated autocorrelation time, the implied
+/// effective sample size and the suggested thinning interval.
+pub struct Mixing {
+    pub name: String,
+    pub tau: f64,
+    pub ess: f64,
+    pub thin: usize,
+}
+
+/// Names of the parameters of the draws, see [`data::parameter_names`].
+pub fn names<P: Draw>(samples: &[P]) -> Vec<String> {
+    data::parameter_names(data::n_traits_of(samples.first().map_or(0, |p| p.values().len())))
+}
+
+/// `traces[d]` is the trace of parameter `d`.
+pub fn traces<P: Draw>(samples: &[P]) -> Vec<Vec<f64>> {
+    let values: Vec<Vec<f64>> = samples.iter().map(|p| p.values()).collect();
+    let n = values.first().map_or(0, |v| v.len());
+    (0..n).map(|d| values.iter().map(|v| v[d]).collect()).collect()
+}
+
+/// Mean and 5-95% interval of every parameter, with the ESS and MCSE columns of [`summary_columns`].
+pub fn summary<P: Draw>(samples: &[P]) -> String {
+    summary_of(&[traces(samples)], &names(samples))
+}
+
+/// Like [`summary`], for draws of several chains that were run together, e.g. the walkers of
+/// [`crate::ensemble`] or the chains of [`crate::demc`]: the mean and the interval are those of all the draws,
+/// the ESS and MCSE columns treat every chain as a chain of its own.
+pub fn summary_chains<P: Draw>(chains: &[Vec<P>]) -> String {
+    let traces: Vec<Vec<Vec<f64>>> = chains.iter().map(|c| traces(c)).collect();
+    summary_of(&traces, &chains.first().map_or_else(Vec::new, |c| names(c)))
+}
+
+/// Draws ordered by iteration and then by chain, as returned by [`crate::ensemble`] and [`crate::demc`], as one `Vec` per chain.
+pub fn deinterleave<T: Clone>(samples: &[T], n_chains: usize) -> Vec<Vec<T>> {
+    (0..n_chains).map(|c| samples.iter().skip(c).step_by(n_chains).cloned().collect()).collect()
+}
+
+// traces[c][d] is the trace of parameter d in chain c
+fn summary_of(traces: &[Vec<Vec<f64>>], names: &[String]) -> String {
+    names.iter().enumerate()
+        .map(|(d, name)| {
+            let chains: Vec<Vec<f64>> = traces.iter().map(|t| t[d].clone()).collect();
+            let mut all = chains.concat();
+            let n = all.len() as f64;
+            let mean = all.iter().sum::<f64>() / n;
+            // effective sample size and monte carlo standard error, before the draws get sorted
+            let columns = summary_columns(&chains);
+            all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            format!("{}: {:.3} [{:.3}, {:.3}] {}", name, mean, all[(n*0.05) as usize], all[(n*0.95) as usize], columns)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Autocorrelation function of every parameter, up to `max_lag`.
+pub fn autocorrelations<P: Draw>(samples: &[P], max_lag: usize) -> Vec<Vec<f64>> {
+    traces(samples).iter()
+        .map(|t| {
+            let mut rho = autocorrelation(t);
+            rho.truncate(max_lag + 1);
+            rho
+        })
+        .collect()
+}
+
+/// Mixing of every parameter of the draws of a single chain.
+pub fn mixing<P: Draw>(samples: &[P]) -> Vec<Mixing> {
+    let n = samples.len() as f64;
+    traces(samples).iter().zip(names(samples))
+        .map(|(t, name)| {
+            let tau = integrated_autocorrelation_time(t);
+            Mixing { name, tau, ess: n / tau, thin: thinning_interval(tau) }
+        })
+        .collect()
+}
+
+/// One line per parameter with its autocorrelation time, ESS and suggested thinning.
+pub fn mixing_summary<P: Draw>(samples: &[P]) -> String {
+    mixing(samples).iter()
+        .map(|m| format!("{}: autocorrelation time {:.1}, ESS {:.0}, thin by {}", m.name, m.tau, m.ess, m.thin))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Normalised autocorrelation at lags `0..n`, computed with a zero padded FFT in O(n log n).
+pub fn autocorrelation(x: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let mean = x.iter().sum::<f64>() / n as f64;
+
+    // padding to at least 2n avoids the circular wrap-around
+    let size = (2 * n).next_power_of_two();
+    let mut buffer: Vec<Complex<f64>> = x.iter()
+        .map(|v| Complex::new(v - mean, 0.0))
+        .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+        .take(size)
+        .collect();
+
+    let mut planner = FftPlanner::new();
+    planner.plan_fft_forward(size).process(&mut buffer);
+    for c in buffer.iter_mut() {
+        *c = Complex::new(c.norm_sqr(), 0.0);
+    }
+    planner.plan_fft_inverse(size).process(&mut buffer);
+
+    let c0 = buffer[0].re;
+    if c0 <= 0.0 {
+        let mut rho = vec![0.0; n];
+        rho[0] = 1.0;
+        return rho;
+    }
+    buffer[..n].iter().map(|c| c.re / c0).collect()
+}
+
+/// Integrated autocorrelation time of a single trace, see [`integrated_time_from_acf`].
+pub fn integrated_autocorrelation_time(x: &[f64]) -> f64 {
+    integrated_time_from_acf(&autocorrelation(x))
+}
+
+/// `tau = 1 + 2 sum_{t=1}^M rho(t)` from the autocorrelations `rho`, truncated at the first M with `M >= 5 tau(M)` (Sokal).
+pub fn integrated_time_from_acf(rho: &[f64]) -> f64 {
+    let mut tau = 1.0;
+    for (lag, r) in rho.iter().enumerate().skip(1) {
+        tau += 2.0 * r;
+        if lag as f64 >= ACF_WINDOW * tau {
+            break;
+        }
+    }
+    tau.max(1.0)
+}
+
+/// Keeping every `ceil(tau)`-th draw leaves roughly independent draws.
+pub fn thinning_interval(tau: f64) -> usize {
+    tau.ceil().max(1.0) as usize
+}
+
+/// Every `k`-th draw, starting with the first.
+pub fn thin<T: Clone>(samples: &[T], k: usize) -> Vec<T> {
+    samples.iter().step_by(k.max(1)).cloned().collect()
+}
+
+/// Geweke z-score: difference of the means of the first and the last part of the chain,
+/// standardised by the spectral density at zero of each part.
+pub fn geweke(x: &[f64], first: f64, last: f64) -> f64 {
+    let n = x.len();
+    let a = &x[..(first * n as f64) as usize];
+    let b = &x[n - (last * n as f64) as usize..];
+
+    if a.len() < 2 || b.len() < 2 {
+        return f64::NAN;
+    }
+
+    (mean(a) - mean(b)) / (spectrum0(a) / a.len() as f64 + spectrum0(b) / b.len() as f64).sqrt()
+}
+
+/// The result of [`heidelberger_welch`].
+pub struct HeidelbergerWelch {
+    /// Number of draws to discard before the chain looks stationary, `None` if it never does.
+    pub start: Option<usize>,
+    /// p-value of the last stationarity test.
+    pub p_value: f64,
+    /// Mean of the kept draws and the half-width of its 95% interval, NaN if the chain is not stationary.
+    pub mean: f64,
+    pub halfwidth: f64,
+    pub halfwidth_pass: bool,
+}
+
+/// Heidelberger & Welch (1983): the Cramer-von Mises test of stationarity is applied to the
+/// chain after discarding the first 0%, 10%, ..., 50% of it, until it passes; the
+/// half-width test then checks that the mean of the remaining draws is estimated accurately.
+pub fn heidelberger_welch(x: &[f64]) -> HeidelbergerWelch {
+    let n = x.len();
+    // the spectral density at zero is estimated once, from the second half of the chain
+    let s0 = spectrum0(&x[n / 2..]);
+
+    let mut p_value = f64::NAN;
+    // e.g. a constant trace, which has no spectral density to standardise by
+    if !(s0 > 0.0 && s0.is_finite()) {
+        return HeidelbergerWelch { start: None, p_value, mean: mean(x), halfwidth: f64::NAN, halfwidth_pass: false };
+    }
+    for k in 0..=5 {
+        let start = k * n / 10;
+        let y = &x[start..];
+        let m = y.len();
+
+        let ybar = mean(y);
+        let mut cumsum = 0.0;
+        let mut statistic = 0.0;
+        for (i, v) in y.iter().enumerate() {
+            cumsum += v;
+            let bridge = cumsum - ybar * (i + 1) as f64;
+            statistic += bridge * bridge / (m as f64 * s0);
+        }
+        statistic /= m as f64;
+
+        p_value = 1.0 - cramer_von_mises_cdf(statistic);
+
+        if p_value > ALPHA {
+            let halfwidth = Normal::new(0.0, 1.0).unwrap().inverse_cdf(1.0 - ALPHA / 2.0) * (spectrum0(y) / m as f64).sqrt();
+            return HeidelbergerWelch {
+                start: Some(start),
+                p_value,
+                mean: ybar,
+                halfwidth,
+                halfwidth_pass: halfwidth < HALFWIDTH_EPS * ybar.abs(),
+            };
+        }
+    }
+
+    HeidelbergerWelch {
+        start: None,
+        p_value,
+        mean: mean(x),
+        halfwidth: f64::NAN,
+        halfwidth_pass: false,
+    }
+}
+
+/// Stationarity of a single parameter.
+pub struct Stationarity {
+    pub name: String,
+    pub geweke_z: f64,
+    pub heidelberger_welch: HeidelbergerWelch,
+}
+
+impl Stationarity {
+    /// Both the Geweke and the Heidelberger-Welch stationarity tests pass at the 5% level.
+    pub fn is_stationary(&self) -> bool {
+        let critical = Normal::new(0.0, 1.0).unwrap().inverse_cdf(1.0 - ALPHA / 2.0);
+        self.geweke_z.abs() < critical && self.heidelberger_welch.start.is_some()
+    }
+}
+
+/// Stationarity of every parameter of the draws of a single chain.
+pub fn stationarity<P: Draw>(samples: &[P]) -> Vec<Stationarity> {
+    traces(samples).iter().zip(names(samples))
+        .map(|(t, name)| Stationarity {
+            name,
+            geweke_z: geweke(t, GEWEKE_FIRST, GEWEKE_LAST),
+            heidelberger_welch: heidelberger_welch(t),
+        })
+        .collect()
+}
+
+/// The largest Heidelberger-Welch start over the parameters, `None` if some parameter is not stationary.
+pub fn suggested_burnin<P: Draw>(samples: &[P]) -> Option<usize> {
+    stationarity(samples).iter()
+        .map(|s| s.heidelberger_welch.start)
+        .try_fold(0, |acc, start| start.map(|s| acc.max(s)))
+}
+
+/// One line per parameter with its Geweke and Heidelberger-Welch tests.
+pub fn stationarity_summary<P: Draw>(samples: &[P]) -> String {
+    stationarity(samples).iter()
+        .map(|s| {
+            let hw = &s.heidelberger_welch;
+            let start = match hw.start {
+                Some(start) => format!("discard {}", start),
+                None => "not stationary".to_string(),
+            };
+            format!("{}: Geweke z {:.2}, Heidelberger-Welch p {:.3} ({}), mean {:.3} +/- {:.4} (half-width test {}){}",
+                s.name, s.geweke_z, hw.p_value, start, hw.mean, hw.halfwidth,
+                if hw.halfwidth_pass { "passed" } else { "failed" },
+                if s.is_stationary() { "" } else { " <- has not reached stationarity" })
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// spectral density at frequency zero, variance times the integrated autocorrelation time
+fn spectrum0(x: &[f64]) -> f64 {
+    let m = mean(x);
+    let var = x.iter().map(|v| (v - m).powi(2)).sum::<f64>() / x.len() as f64;
+    var * integrated_autocorrelation_time(x)
+}
+
+fn mean(x: &[f64]) -> f64 {
+    x.iter().sum::<f64>() / x.len() as f64
+}
+
+// limiting distribution of the Cramer-von Mises statistic (Anderson & Darling 1952),
+// the series is the one used by coda's pcramer
+fn cramer_von_mises_cdf(q: f64) -> f64 {
+    if !q.is_finite() {
+        return f64::NAN;
+    }
+    if q <= 0.0 {
+        return 0.0;
+    }
+    (0..4)
+        .map(|k| {
+            let k = k as f64;
+            let u = (4.0 * k + 1.0).powi(2) / (16.0 * q);
+            if u > 1e5_f64.ln() {
+                return 0.0;
+            }
+            let z = gamma(k + 0.5) * (4.0 * k + 1.0).sqrt() / (gamma(k + 1.0) * std::f64::consts::PI.powf(1.5) * q.sqrt());
+            z * (-u).exp() * bessel_k(0.25, u)
+        })
+        .sum::<f64>()
+        .min(1.0)
+}
+
+// modified Bessel function of the second kind, K_nu(x) = int_0^inf exp(-x cosh t) cosh(nu t) dt,
+// by the trapezoidal rule, truncated at t = BESSEL_T_MAX
+fn bessel_k(nu: f64, x: f64) -> f64 {
+    let h: f64 = 1e-3;
+    let mut sum = 0.5 * (-x).exp();
+    let mut t = h;
+    while t < BESSEL_T_MAX {
+        let f = (-x * t.cosh()).exp() * (nu * t).cosh();
+        sum += f;
+        if f < 1e-16 * sum {
+            break;
+        }
+        t += h;
+    }
+    sum * h
+}
+
+/// Effective sample size of one or more chains of the same length, following
+/// Stan: the autocorrelations are combined across chains and summed over
+/// Geyer's initial monotone sequence.
+pub fn ess(chains: &[Vec<f64>]) -> f64 {
+    let m = chains.len();
+    let n = chains.iter().map(|c| c.len()).min().unwrap();
+    let chains: Vec<&[f64]> = chains.iter().map(|c| &c[..n]).collect();
+
+    if n < 4 {
+        return f64::NAN;
+    }
+
+    let nf = n as f64;
+    let means: Vec<f64> = chains.iter().map(|c| c.iter().sum::<f64>() / nf).collect();
+    let centred: Vec<Vec<f64>> = chains.iter().zip(means.iter())
+        .map(|(c, mean)| c.iter().map(|x| x - mean).collect())
+        .collect();
+
+    // mean over the chains of the (biased) autocovariance at the given lag
+    let acov = |lag: usize| -> f64 {
+        centred.iter()
+            .map(|c| c[..n - lag].iter().zip(c[lag..].iter()).map(|(a, b)| a * b).sum::<f64>() / nf)
+            .sum::<f64>() / m as f64
+    };
+
+    let acov0 = acov(0);
+    let w = acov0 * nf / (nf - 1.0);
+    let between = if m > 1 {
+        let grand_mean = means.iter().sum::<f64>() / m as f64;
+        means.iter().map(|x| (x - grand_mean).powi(2)).sum::<f64>() / (m as f64 - 1.0)
+    } else {
+        0.0
+    };
+    let var_plus = w * (nf - 1.0) / nf + between;
+
+    if var_plus <= 0.0 || !var_plus.is_finite() {
+        return f64::NAN;
+    }
+
+    let rho_at = |lag: usize| 1.0 - (w - acov(lag)) / var_plus;
+
+    let mut rho = vec![0.0; n];
+    rho[0] = 1.0;
+    let mut rho_even = 1.0;
+    let mut rho_odd = rho_at(1);
+    rho[1] = rho_odd;
+
+    // positive sequence: pairs are added while their sum stays positive
+    let mut t = 1;
+    while t + 5 < n && rho_even + rho_odd > 0.0 {
+        rho_even = rho_at(t + 1);
+        rho_odd = rho_at(t + 2);
+        if rho_even + rho_odd >= 0.0 {
+            rho[t + 1] = rho_even;
+            rho[t + 2] = rho_odd;
+        }
+        t += 2;
+    }
+    let max_t = t;
+    if rho_even > 0.0 {
+        rho[max_t + 1] = rho_even;
+    }
+
+    // monotone sequence: the pair sums may not increase
+    let mut t = 1;
+    while t + 2 <= max_t {
+        if rho[t + 1] + rho[t + 2] > rho[t - 1] + rho[t] {
+            rho[t + 1] = (rho[t - 1] + rho[t]) / 2.0;
+            rho[t + 2] = rho[t + 1];
+        }
+        t += 2;
+    }
+
+    let s = (m * n) as f64;
+    let tau = (-1.0 + 2.0 * rho[..max_t].iter().sum::<f64>() + rho[max_t + 1]).max(1.0 / s.log10());
+    s / tau
+}
+
+/// Bulk-ESS, the ESS of the rank normalised split chains.
+pub fn bulk_ess(chains: &[Vec<f64>]) -> f64 {
+    ess(&rank_normalise(&split(chains)))
+}
+
+/// Tail-ESS, the smaller ESS of the indicators of the 5% and 95% quantiles.
+pub fn tail_ess(chains: &[Vec<f64>]) -> f64 {
+    let all: Vec<f64> = chains.iter().flatten().cloned().collect();
+    let split_chains = split(chains);
+    quantile_ess(&split_chains, quantile(&all, 0.05)).min(quantile_ess(&split_chains, quantile(&all, 0.95)))
+}
+
+/// Monte Carlo standard error of the mean.
+pub fn mcse_mean(chains: &[Vec<f64>]) -> f64 {
+    let all: Vec<f64> = chains.iter().flatten().cloned().collect();
+    let n = all.len() as f64;
+    let mean = all.iter().sum::<f64>() / n;
+    let sd = (all.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    sd / ess(&split(chains)).sqrt()
+}
+
+/// Monte Carlo standard error of the `p`-quantile, from the beta distribution of the
+/// proportion of draws below it (Vehtari et al. 2021).
+pub fn mcse_quantile(chains: &[Vec<f64>], p: f64) -> f64 {
+    let mut all: Vec<f64> = chains.iter().flatten().cloned().collect();
+    all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let s = all.len();
+
+    let ess_p = quantile_ess(&split(chains), quantile(&all, p));
+    if !ess_p.is_finite() {
+        return f64::NAN;
+    }
+
+    let beta = Beta::new(ess_p * p + 1.0, ess_p * (1.0 - p) + 1.0).unwrap();
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let a = beta.inverse_cdf(normal.cdf(-1.0));
+    let b = beta.inverse_cdf(normal.cdf(1.0));
+
+    let index = |u: f64| ((u * s as f64) as usize).min(s - 1);
+    (all[index(b)] - all[index(a)]) / 2.0
+}
+
+/// The ESS and MCSE columns of the summaries.
+pub fn summary_columns(chains: &[Vec<f64>]) -> String {
+    format!("bulk-ESS: {:.0} tail-ESS: {:.0} MCSE mean: {:.4} MCSE q5: {:.4} MCSE q95: {:.4}",
+        bulk_ess(chains), tail_ess(chains), mcse_mean(chains), mcse_quantile(chains, 0.05), mcse_quantile(chains, 0.95))
+}
+
+fn quantile_ess(chains: &[Vec<f64>], q: f64) -> f64 {
+    let indicators: Vec<Vec<f64>> = chains.iter()
+        .map(|c| c.iter().map(|&x| if x <= q { 1.0 } else { 0.0 }).collect())
+        .collect();
+    ess(&indicators)
+}
+
+// every chain cut into two halves
+fn split(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    chains.iter()
+        .flat_map(|c| {
+            let n = c.len() / 2;
+            [c[..n].to_vec(), c[n..2 * n].to_vec()]
+        })
+        .collect()
+}
+
+/// Normal scores `z = Phi^-1((r - 3/8) / (S + 1/4))` of the draws, with r the rank of a draw among all S draws (average rank for ties).
+pub fn rank_normalise(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let mut all: Vec<(f64, usize, usize)> = chains.iter().enumerate()
+        .flat_map(|(c, chain)| chain.iter().enumerate().map(move |(i, &x)| (x, c, i)))
+        .collect();
+    all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let s = all.len() as f64;
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let mut z: Vec<Vec<f64>> = chains.iter().map(|c| vec![0.0; c.len()]).collect();
+
+    let mut i = 0;
+    while i < all.len() {
+        let mut j = i;
+        while j + 1 < all.len() && all[j + 1].0 == all[i].0 {
+            j += 1;
+        }
+        // ranks are 1-based
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        let score = normal.inverse_cdf((rank - 0.375) / (s + 0.25));
+        for &(_, c, k) in all[i..=j].iter() {
+            z[c][k] = score;
+        }
+        i = j + 1;
+    }
+
+    z
+}
+
+/// The `q`-quantile of `x`, with the convention of the summaries: the element at index `floor(n q)` of the sorted draws.
+pub fn quantile(x: &[f64], q: f64) -> f64 {
+    let mut v = x.to_vec();
+    v.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    v[((v.len() as f64 * q) as usize).min(v.len() - 1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(n: usize) -> Vec<mh::Parameters> {
+        (0..n).map(|i| mh::Parameters::from_slice(&[0.1 + (i as f64 * 0.7).sin().abs(), 0.5, i as f64, (i * i) as f64 % 3.0, 0.0, 1.0])).collect()
+    }
+
+    // the ESS of chains of four and five draws used to underflow
+    #[test]
+    fn short_chains_have_an_ess_and_a_summary() {
+        for n in 1..8 {
+            let x: Vec<f64> = (0..n).map(|i| (i as f64 * 1.3).cos()).collect();
+            let ess = ess(&[x.clone(), x.iter().map(|v| v + 0.1).collect()]);
+            assert_eq!(ess.is_nan(), n < 4, "{} draws: ESS {}", n, ess);
+            summary_columns(&[x]);
+            summary(&draws(n));
+        }
+    }
+
+    #[test]
+    fn interleaved_draws_are_split_into_their_chains() {
+        let chains = deinterleave(&[1, 10, 2, 20, 3, 30], 2);
+        assert_eq!(chains, [vec![1, 2, 3], vec![10, 20, 30]]);
+        assert_eq!(summary_chains(&[draws(40)]), summary(&draws(40)));
+    }
+
+    // walkers that each stay at their own value look like one chain that mixes well when pooled,
+    // and like chains that never move when they are kept apart
+    #[test]
+    fn the_ess_of_interleaved_walkers_is_computed_per_walker() {
+        let n_walkers = 4;
+        let samples: Vec<f64> = (0..400).map(|i| (i % n_walkers) as f64 + (i as f64 * 0.37).sin() * 1e-3).collect();
+        let pooled = ess(std::slice::from_ref(&samples));
+        let per_walker = ess(&deinterleave(&samples, n_walkers));
+        assert!(per_walker < pooled / 10.0, "per walker {}, pooled {}", per_walker, pooled);
+    }
+}
@@ -0,0 +1,191 @@
+//! Maximum likelihood fit of the linear mixing model.
+//!
+//! The groups have means
+//!
+//! ```text
+//! group 1: mu, group 2: gamma, group 3: (mu + gamma)/2, group 4: tau mu + (1 - tau) gamma,
+//! ```
+//!
+//! with a common variance `s = sigma^2` for all traits. Unlike the samplers, this uses the
+//! likelihood alone, without the priors of [`crate::mh::log_posterior`].
+
+use crate::data::{self, Data, Row};
+use crate::linalg;
+use crate::map;
+
+static MAX_ITER: usize = 10000;
+static TOLERANCE: f64 = 1e-12;
+
+/// The maximum likelihood estimates and their standard errors, in the order
+/// `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`.
+pub struct Output {
+    pub estimate: Vec<f64>,
+    pub std_errors: Vec<f64>,
+    pub log_likelihood: f64,
+    pub n_iter: usize,
+    pub converged: bool,
+}
+
+/// Alternates the closed form least squares updates of `(mu, gamma)` given tau and of tau
+/// given `(mu, gamma)`; every step increases the likelihood, like the M step of an EM algorithm.
+pub fn run(data: &Data) -> Output {
+    let mut mu = data::group_mean(data, 1);
+    let mut gamma = data::group_mean(data, 2);
+    let mut tau = 0.5;
+
+    let mut rss = residual_sum_of_squares(data, tau, &mu, &gamma);
+    let mut converged = false;
+    let mut n_iter = 0;
+
+    for iter in 1..=MAX_ITER {
+        n_iter = iter;
+
+        (mu, gamma) = update_means(data, tau, &mu, &gamma);
+        tau = update_tau(data, tau, &mu, &gamma);
+
+        let new_rss = residual_sum_of_squares(data, tau, &mu, &gamma);
+        let change = (rss - new_rss) / new_rss;
+        rss = new_rss;
+
+        if change.abs() < TOLERANCE {
+            converged = true;
+            break;
+        }
+    }
+
+    // mle of the variance, D traits per row
+    let s = rss / ((data::n_traits(data) * data.len()) as f64);
+    let mut estimate = vec![s, tau];
+    estimate.extend(&mu);
+    estimate.extend(&gamma);
+
+    // standard errors from the inverse of the observed information -d^2 log L
+    let hessian = map::hessian(|theta| log_likelihood_grad(data, theta), &estimate);
+    let information: linalg::Matrix = hessian.iter().map(|row| row.iter().map(|h| -h).collect()).collect();
+    let std_errors = match linalg::inverse_spd(&information) {
+        Some(cov) => (0..cov.len()).map(|i| cov[i][i].sqrt()).collect(),
+        None => vec![f64::NAN; estimate.len()],
+    };
+
+    Output {
+        log_likelihood: log_likelihood(data, &estimate),
+        estimate,
+        std_errors,
+        n_iter,
+        converged,
+    }
+}
+
+// weights of mu and gamma in the mean of each group
+fn weights(group: u8, tau: f64) -> (f64, f64) {
+    match group {
+        1 => (1.0, 0.0),
+        2 => (0.0, 1.0),
+        3 => (0.5, 0.5),
+        4 => (tau, 1.0 - tau),
+        _ => unreachable!(),
+    }
+}
+
+fn residuals(r: &Row, tau: f64, mu: &[f64], gamma: &[f64]) -> Vec<f64> {
+    let (a, b) = weights(r.group, tau);
+    (0..r.x.len()).map(|k| r.x[k] - a * mu[k] - b * gamma[k]).collect()
+}
+
+fn residual_sum_of_squares(data: &Data, tau: f64, mu: &[f64], gamma: &[f64]) -> f64 {
+    data.iter()
+        .map(|r| residuals(r, tau, mu, gamma).iter().map(|e| e * e).sum::<f64>())
+        .sum()
+}
+
+// least squares for (mu_k, gamma_k) given tau, the same 2x2 normal equations for every trait
+fn update_means(data: &Data, tau: f64, mu: &[f64], gamma: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let d = data::n_traits(data);
+    let (mut saa, mut sab, mut sbb) = (0.0, 0.0, 0.0);
+    let mut sax = vec![0.0; d];
+    let mut sbx = vec![0.0; d];
+
+    for r in data.iter() {
+        let (a, b) = weights(r.group, tau);
+        saa += a * a;
+        sab += a * b;
+        sbb += b * b;
+        for (k, x) in r.x.iter().enumerate() {
+            sax[k] += a * x;
+            sbx[k] += b * x;
+        }
+    }
+
+    let det = saa * sbb - sab * sab;
+    if det.abs() < 1e-12 {
+        return (mu.to_vec(), gamma.to_vec());
+    }
+
+    let new_mu = (0..d).map(|k| (sbb * sax[k] - sab * sbx[k]) / det).collect();
+    let new_gamma = (0..d).map(|k| (saa * sbx[k] - sab * sax[k]) / det).collect();
+    (new_mu, new_gamma)
+}
+
+// least squares for tau given (mu, gamma), restricted to [0, 1]
+fn update_tau(data: &Data, tau: f64, mu: &[f64], gamma: &[f64]) -> f64 {
+    let d: Vec<f64> = mu.iter().zip(gamma.iter()).map(|(m, g)| m - g).collect();
+    let dd: f64 = d.iter().map(|dk| dk * dk).sum();
+
+    let group4: Vec<&Row> = data.iter().filter(|r| r.group == 4).collect();
+    if group4.is_empty() || dd == 0.0 {
+        return tau;
+    }
+
+    let numer: f64 = group4.iter()
+        .map(|r| (0..d.len()).map(|k| (r.x[k] - gamma[k]) * d[k]).sum::<f64>())
+        .sum();
+
+    (numer / (group4.len() as f64 * dd)).clamp(0.0, 1.0)
+}
+
+/// Gaussian log likelihood of `theta = (s, tau, mu1, ..., muD, gamma1, ..., gammaD)`.
+pub fn log_likelihood(data: &Data, theta: &[f64]) -> f64 {
+    let d = data::n_traits(data);
+    // half the number of values, i.e. the number of rows for two traits
+    let n = data.len() as f64 * d as f64 / 2.0;
+    let rss = residual_sum_of_squares(data, theta[1], &theta[2..2 + d], &theta[2 + d..2 + 2 * d]);
+    -n * (std::f64::consts::TAU * theta[0]).ln() - rss / (2.0 * theta[0])
+}
+
+fn log_likelihood_grad(data: &Data, theta: &[f64]) -> Vec<f64> {
+    let d = data::n_traits(data);
+    let (s, tau) = (theta[0], theta[1]);
+    let (mu, gamma) = (&theta[2..2 + d], &theta[2 + d..2 + 2 * d]);
+    let n = data.len() as f64 * d as f64 / 2.0;
+
+    let mut grad = vec![0.0; theta.len()];
+    let mut rss = 0.0;
+
+    for r in data.iter() {
+        let e = residuals(r, tau, mu, gamma);
+        let (a, b) = weights(r.group, tau);
+        rss += e.iter().map(|ek| ek * ek).sum::<f64>();
+
+        if r.group == 4 {
+            grad[1] += (0..d).map(|k| e[k] * (mu[k] - gamma[k])).sum::<f64>() / s;
+        }
+        for k in 0..d {
+            grad[2 + k] += a * e[k] / s;
+            grad[2 + d + k] += b * e[k] / s;
+        }
+    }
+
+    grad[0] = -n / s + rss / (2.0 * s * s);
+    grad
+}
+
+impl Output {
+    /// One line per parameter, with its estimate and standard error.
+    pub fn summary(&self) -> String {
+        let names = data::parameter_names(data::n_traits_of(self.estimate.len()));
+        names.iter().zip(self.estimate.iter().zip(self.std_errors.iter()))
+            .map(|(name, (e, se))| format!("{}: {:.3} (se {:.3})", name, e, se))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
@@ -0,0 +1,169 @@
+//! Affine-invariant ensemble sampling with the stretch move of Goodman & Weare (2010).
+//!
+//! Every walker targets [`crate::mh::log_posterior`]. The draws of the walkers are returned
+//! interleaved, ordered by iteration and then by walker; [`crate::diagnostics::deinterleave`]
+//! splits them into one chain per walker.
+
+use crate::data::{self, Data};
+use crate::diagnostics;
+use crate::mh::{self, Parameters};
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::{Uniform, Normal};
+
+// scale parameter of the stretch distribution g(z) ~ 1/sqrt(z) on [1/a, a]
+static STRETCH: f64 = 2.0;
+
+/// An ensemble of walkers, each moved along the line through another walker.
+pub struct Chain {
+    data: Data,
+    // number of parameters, 2 + 2D
+    n_dim: usize,
+    walkers: Vec<Vec<f64>>,
+    log_p: Vec<f64>,
+    accepted: Vec<usize>,
+    n_steps: usize,
+}
+
+impl Chain {
+    /// An ensemble of `n_walkers` walkers, at least two; they are placed by [`Chain::run`].
+    pub fn new(data: Data, n_walkers: usize) -> Self {
+        assert!(n_walkers >= 2, "the stretch move needs at least two walkers");
+
+        let n_dim = 2 + 2 * data::n_traits(&data);
+
+        Self {
+            data,
+            n_dim,
+            walkers: vec![vec![0.0; n_dim]; n_walkers],
+            log_p: vec![f64::NEG_INFINITY; n_walkers],
+            accepted: vec![0; n_walkers],
+            n_steps: 0,
+        }
+    }
+
+    /// Runs `n_burnin` iterations, then returns `n_samples` draws from every walker, ordered by iteration and then by walker.
+    pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.init_walkers(&mut rng);
+
+        for _ in 0..n_burnin {
+            self.step(&mut rng);
+        }
+
+        // only report acceptance after the burn-in
+        self.accepted.iter_mut().for_each(|a| *a = 0);
+        self.n_steps = 0;
+
+        let mut samples = Vec::with_capacity(n_samples * self.walkers.len());
+
+        for _ in 0..n_samples {
+            self.step(&mut rng);
+            for w in self.walkers.iter() {
+                samples.push(Parameters::from_slice(w));
+            }
+        }
+
+        samples
+    }
+
+    /// Number of walkers.
+    pub fn n_walkers(&self) -> usize {
+        self.walkers.len()
+    }
+
+    /// Fraction of accepted moves of every walker since the burn-in, zero before the first step after it.
+    pub fn acceptance_rates(&self) -> Vec<f64> {
+        self.accepted.iter().map(|&a| a as f64 / self.n_steps.max(1) as f64).collect()
+    }
+
+    // walkers start in a small ball around the starting point of `mh::Chain`
+    fn init_walkers(&mut self, rng: &mut StdRng) {
+        let ball = Normal::new(0.0, 0.1).unwrap();
+        let unif = Uniform::new(0.0, 1.0).unwrap();
+
+        for k in 0..self.walkers.len() {
+            let mut w = vec![1.0 + ball.sample(rng), unif.sample(rng)];
+            w.extend((2..self.n_dim).map(|_| ball.sample(rng)));
+            self.log_p[k] = mh::log_posterior(&self.data, &Parameters::from_slice(&w));
+            self.walkers[k] = w;
+        }
+    }
+
+    // serial Goodman & Weare stretch move, one update per walker
+    fn step(&mut self, rng: &mut StdRng) {
+        let n = self.walkers.len();
+
+        for k in 0..n {
+            let mut j = rng.gen_range(0..n - 1);
+            if j >= k {
+                j += 1;
+            }
+
+            let z = ((STRETCH - 1.0) * rng.gen::<f64>() + 1.0).powi(2) / STRETCH;
+
+            let proposal: Vec<f64> = self.walkers[j].iter()
+                .zip(self.walkers[k].iter())
+                .map(|(xj, xk)| xj + z * (xk - xj))
+                .collect();
+
+            let new_log_p = mh::log_posterior(&self.data, &Parameters::from_slice(&proposal));
+            let log_ratio = (self.n_dim as f64 - 1.0) * z.ln() + new_log_p - self.log_p[k];
+
+            if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+                self.walkers[k] = proposal;
+                self.log_p[k] = new_log_p;
+                self.accepted[k] += 1;
+            }
+        }
+
+        self.n_steps += 1;
+    }
+}
+
+/// Integrated autocorrelation time of each parameter, estimated from the
+/// autocorrelation function averaged over the walkers, as in emcee.
+pub fn autocorrelation_time(samples: &[Parameters], n_walkers: usize) -> Vec<f64> {
+    let n = samples.len() / n_walkers;
+
+    let traces: Vec<Vec<f64>> = samples.iter().map(|p| p.to_vec()).collect();
+
+    let n_dim = traces.first().map_or(0, |t| t.len());
+
+    (0..n_dim).map(|d| {
+        let mut rho = vec![0.0; n];
+        for k in 0..n_walkers {
+            let x: Vec<f64> = (0..n).map(|t| traces[t * n_walkers + k][d]).collect();
+            for (r, rk) in rho.iter_mut().zip(diagnostics::autocorrelation(&x)) {
+                *r += rk / n_walkers as f64;
+            }
+        }
+        diagnostics::integrated_time_from_acf(&rho)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Row;
+
+    // group 4 has the mean of group 1, so that the posterior of tau piles up against its bound at 1
+    fn data() -> Data {
+        (0..40).map(|i| {
+            let m = match i % 4 { 0 | 3 => -1.0, 1 => 1.0, _ => 0.0 };
+            Row { group: (i % 4 + 1) as u8, x: vec![m + 0.3 * (i as f64 * 0.7).sin(), -m + 0.3 * (i as f64 * 1.1).cos()] }
+        }).collect()
+    }
+
+    fn inside(q: &[f64]) -> bool {
+        q[0] > 0.0 && q[0] <= 10.0 && q[1] > 0.0 && q[1] < 1.0
+    }
+
+    #[test]
+    fn stretch_moves_keep_the_walkers_inside_the_support() {
+        let samples = Chain::new(data(), 16).run(200, 300, 3);
+        let tau: Vec<f64> = samples.iter().map(|p| p.to_vec()[1]).collect();
+        assert!(samples.iter().all(|p| inside(&p.to_vec())));
+        assert!(tau.iter().cloned().fold(0.0, f64::max) > 0.95);
+    }
+}
@@ -1,42 +1,55 @@
-use crate::data::{Data};
+//! Gibbs sampler.
+
+#![allow(non_snake_case)]
+
+use crate::data::{self, Data};
+use crate::{checkpoint, diagnostics, metadata, sink, stan};
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
 use rand::distributions::Distribution;
 use serde::{Serialize, Deserialize};
+use color_eyre::Result;
 use statrs::distribution::{Normal, ChiSquared};
 
-static SPROPSD: f64 = 0.1;
-static MEANPROPSD: f64 = 0.5;
-
+/// A draw of `(s, tau, mu, gamma)` with one `mu` and `gamma` per trait.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Parameters {
     s: f64,
     tau: f64,
-    mu1: f64,
-    mu2: f64,
-    gamma1: f64,
-    gamma2: f64,
+    mu: Vec<f64>,
+    gamma: Vec<f64>,
 }
 
+/// A Gibbs sampler drawing `s`, `tau`, `mu` and `gamma` from their full conditionals in turn.
 pub struct Chain {
     data: Data,
     parameters: Parameters,
 }
 
 impl Chain {
+    /// A chain started at `s = 0.06`, `tau = 0.8` and the means of groups 1 and 2.
     pub fn new(data: Data) -> Self {
+        let mut start = vec![0.06, 0.8];
+        start.extend(data::group_mean(&data, 1));
+        start.extend(data::group_mean(&data, 2));
+        Self::with_start(data, &start)
+    }
+
+    /// `start` is `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`, e.g. the mode found by [`crate::map::run`].
+    pub fn with_start(data: Data, start: &[f64]) -> Self {
+        let d = data::n_traits_of(start.len());
         let parameters = Parameters {
-            s: 0.06,
-            tau: 0.8,
-            mu1: -1.4,
-            mu2: -0.7,
-            gamma1: -0.2,
-            gamma2: 0.3,
+            s: start[0],
+            tau: start[1],
+            mu: start[2..2 + d].to_vec(),
+            gamma: start[2 + d..2 + 2 * d].to_vec(),
         };
         Self { data, parameters }
     }
 
+    /// Runs `n_burnin` iterations, then returns the next `n_samples` states.
     pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
-        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
         for _ in 0..n_burnin {
             self.step(&mut rng);
         }
@@ -51,7 +64,7 @@ impl Chain {
         samples
     }
 
-    fn step(&mut self, rng: &mut StdRng) {
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
         self.update_s(rng);
         self.update_tau(rng);
         self.update_mu(rng);
@@ -59,23 +72,21 @@ impl Chain {
     }
 
 
-    fn update_s(&mut self, rng: &mut StdRng) {
-        let N = self.data.len() as f64;
-        let chisq = ChiSquared::new(2.*N).unwrap();
+    fn update_s(&mut self, rng: &mut ChaCha12Rng) {
+        let N = (data::n_traits(&self.data) * self.data.len()) as f64;
+        let chisq = ChiSquared::new(N).unwrap();
 
         self.parameters.s = 1./chisq.sample(rng);
     }
 
-    fn update_tau(&mut self, rng: &mut StdRng) {
+    fn update_tau(&mut self, rng: &mut ChaCha12Rng) {
         let n4 = self.data.iter().filter(|r| r.group == 4).count() as f64;
 
-        let x41mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x1).sum::<f64>()/n4;
-        let x42mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x2).sum::<f64>()/n4;
+        let mean4 = data::group_mean(&self.data, 4);
 
-        let denom = n4 * (self.parameters.mu1 - self.parameters.gamma1).powi(2) 
-                  + n4 * (self.parameters.mu2 - self.parameters.gamma2).powi(2);
-        let numer = n4 * (self.parameters.mu1 - self.parameters.gamma1)*(x41mean - self.parameters.gamma1) 
-        + n4 * (self.parameters.mu2 - self.parameters.gamma2)*(x42mean - self.parameters.gamma2);
+        let (mu, gamma) = (&self.parameters.mu, &self.parameters.gamma);
+        let denom: f64 = (0..mu.len()).map(|k| n4 * (mu[k] - gamma[k]).powi(2)).sum();
+        let numer: f64 = (0..mu.len()).map(|k| n4 * (mu[k] - gamma[k])*(mean4[k] - gamma[k])).sum();
 
         let n = Normal::new(numer/denom, (self.parameters.s/denom).sqrt()).unwrap();
 
@@ -88,144 +99,116 @@ impl Chain {
         self.parameters.tau = new_tau;
     }
 
-    fn update_mu(&mut self, rng: &mut StdRng) {
+    fn update_mu(&mut self, rng: &mut ChaCha12Rng) {
         let n1 = self.data.iter().filter(|r| r.group == 1).count() as f64;
         let n3 = self.data.iter().filter(|r| r.group == 3).count() as f64;
         let n4 = self.data.iter().filter(|r| r.group == 4).count() as f64;
 
-        let x11mean = self.data.iter().filter(|r| r.group == 1).map(|r| r.x1).sum::<f64>()/n1;
-        let x12mean = self.data.iter().filter(|r| r.group == 1).map(|r| r.x2).sum::<f64>()/n1;
-
-        let x31mean = self.data.iter().filter(|r| r.group == 3).map(|r| r.x1).sum::<f64>()/n3;
-        let x32mean = self.data.iter().filter(|r| r.group == 3).map(|r| r.x2).sum::<f64>()/n3;
-
-        let x41mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x1).sum::<f64>()/n4;
-        let x42mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x2).sum::<f64>()/n4;
-
-        let denom = n1 + n3*0.25 + n4*self.parameters.tau.powi(2);
-        let numer1 = n1*x11mean 
-                   + n3*0.25*(2.*x31mean-self.parameters.gamma1) 
-                   + n4*self.parameters.tau*(x41mean-(1.-self.parameters.tau)*self.parameters.gamma1);
+        let mean1 = data::group_mean(&self.data, 1);
+        let mean3 = data::group_mean(&self.data, 3);
+        let mean4 = data::group_mean(&self.data, 4);
 
-        let numer2 = n1*x12mean 
-                   + n3*0.25*(2.*x32mean-self.parameters.gamma2) 
-                   + n4*self.parameters.tau*(x42mean-(1.-self.parameters.tau)*self.parameters.gamma2);
+        let tau = self.parameters.tau;
+        let gamma = &self.parameters.gamma;
 
-        let n1 = Normal::new(numer1/denom, (self.parameters.s/denom).sqrt()).unwrap();
-        let n2 = Normal::new(numer2/denom, (self.parameters.s/denom).sqrt()).unwrap();
+        let denom = n1 + n3*0.25 + n4*tau.powi(2);
 
-        self.parameters.mu1 = n1.sample(rng);
-        self.parameters.mu2 = n2.sample(rng);
+        // one conditional per trait, drawn in trait order
+        self.parameters.mu = (0..gamma.len())
+            .map(|k| {
+                let numer = n1*mean1[k]
+                          + n3*0.25*(2.*mean3[k]-gamma[k])
+                          + n4*tau*(mean4[k]-(1.-tau)*gamma[k]);
+                Normal::new(numer/denom, (self.parameters.s/denom).sqrt()).unwrap().sample(rng)
+            })
+            .collect();
     }
 
-    fn update_gamma(&mut self, rng: &mut StdRng) { 
+    fn update_gamma(&mut self, rng: &mut ChaCha12Rng) {
         let n2 = self.data.iter().filter(|r| r.group == 2).count() as f64;
         let n3 = self.data.iter().filter(|r| r.group == 3).count() as f64;
         let n4 = self.data.iter().filter(|r| r.group == 4).count() as f64;
 
-        let x21mean = self.data.iter().filter(|r| r.group == 2).map(|r| r.x1).sum::<f64>()/n2;
-        let x22mean = self.data.iter().filter(|r| r.group == 2).map(|r| r.x2).sum::<f64>()/n2;
+        let mean2 = data::group_mean(&self.data, 2);
+        let mean3 = data::group_mean(&self.data, 3);
+        let mean4 = data::group_mean(&self.data, 4);
 
-        let x31mean = self.data.iter().filter(|r| r.group == 3).map(|r| r.x1).sum::<f64>()/n3;
-        let x32mean = self.data.iter().filter(|r| r.group == 3).map(|r| r.x2).sum::<f64>()/n3;
+        let tau = self.parameters.tau;
+        let mu = &self.parameters.mu;
 
-        let x41mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x1).sum::<f64>()/n4;
-        let x42mean = self.data.iter().filter(|r| r.group == 4).map(|r| r.x2).sum::<f64>()/n4;
+        let denom = n2 + n3*0.25 + n4*(1.-tau).powi(2);
 
-        let denom = n2 + n3*0.25 + n4*(1.-self.parameters.tau).powi(2);
-        let numer1 = n2*x21mean 
-                   + n3*0.25*(2.*x31mean-self.parameters.mu1) 
-                   + n4*(1.-self.parameters.tau)*(x41mean-self.parameters.tau*self.parameters.mu1);
+        self.parameters.gamma = (0..mu.len())
+            .map(|k| {
+                let numer = n2*mean2[k]
+                          + n3*0.25*(2.*mean3[k]-mu[k])
+                          + n4*(1.-tau)*(mean4[k]-tau*mu[k]);
+                Normal::new(numer/denom, (self.parameters.s/denom).sqrt()).unwrap().sample(rng)
+            })
+            .collect();
+    }
+}
 
-        let numer2 = n2*x22mean 
-                   + n3*0.25*(2.*x32mean-self.parameters.mu2) 
-                   + n4*(1.-self.parameters.tau)*(x42mean-self.parameters.tau*self.parameters.mu2);
+impl checkpoint::Resumable for Chain {
+    type State = Parameters;
+    type Draw = Parameters;
 
-        let n1 = Normal::new(numer1/denom, (self.parameters.s/denom).sqrt()).unwrap();
-        let n2 = Normal::new(numer2/denom, (self.parameters.s/denom).sqrt()).unwrap();
+    const NAME: &'static str = "gibbs";
 
-        self.parameters.gamma1 = n1.sample(rng);
-        self.parameters.gamma2 = n2.sample(rng);
+    fn data(&self) -> &Data {
+        &self.data
     }
-}
-    
-impl Parameters {
-    pub fn save_to_csv(ps: &Vec<Parameters>, filename: &str) {
-        let mut wtr = csv::Writer::from_path(filename).unwrap();
 
-        for p in ps {
-            wtr.serialize(p).unwrap();
-        }
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
+        Chain::step(self, rng)
     }
 
-    pub fn summary(ps: &Vec<Parameters>) -> String {
-        // compute mean of each parameter
-        let n = ps.len() as f64;
-
-        let mut s = 0.0;
-        let mut tau = 0.0;
-        let mut mu1 = 0.0;
-        let mut mu2 = 0.0;
-        let mut gamma1 = 0.0;
-        let mut gamma2 = 0.0;
-
-        for p in ps {
-            s += p.s;
-            tau += p.tau;
-            mu1 += p.mu1;
-            mu2 += p.mu2;
-            gamma1 += p.gamma1;
-            gamma2 += p.gamma2;
-        }
-        
-        s /= n;
-        tau /= n;
-        mu1 /= n;
-        mu2 /= n;
-        gamma1 /= n;
-        gamma2 /= n;
-
-        // compute 5th and 95th quantiles of each parameter
-        let mut s_vec = Vec::new();
-        let mut tau_vec = Vec::new();
-        let mut mu1_vec = Vec::new();
-        let mut mu2_vec = Vec::new();
-        let mut gamma1_vec = Vec::new();
-        let mut gamma2_vec = Vec::new();
-
-        for p in ps {
-            s_vec.push(p.s);
-            tau_vec.push(p.tau);
-            mu1_vec.push(p.mu1);
-            mu2_vec.push(p.mu2);
-            gamma1_vec.push(p.gamma1);
-            gamma2_vec.push(p.gamma2);
-        }
+    fn state(&self) -> Parameters {
+        self.parameters.clone()
+    }
 
-        s_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        tau_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    fn restore(&mut self, state: &Parameters) {
+        self.parameters = state.clone();
+    }
 
-        let s_5 = s_vec[(n*0.05) as usize];
-        let tau_5 = tau_vec[(n*0.05) as usize];
-        let mu1_5 = mu1_vec[(n*0.05) as usize];
-        let mu2_5 = mu2_vec[(n*0.05) as usize];
-        let gamma1_5 = gamma1_vec[(n*0.05) as usize];
-        let gamma2_5 = gamma2_vec[(n*0.05) as usize];
+    fn draw(&self) -> Parameters {
+        self.parameters.clone()
+    }
+}
 
-        let s_95 = s_vec[(n*0.95) as usize];
-        let tau_95 = tau_vec[(n*0.95) as usize];
-        let mu1_95 = mu1_vec[(n*0.95) as usize];
-        let mu2_95 = mu2_vec[(n*0.95) as usize];
-        let gamma1_95 = gamma1_vec[(n*0.95) as usize];
-        let gamma2_95 = gamma2_vec[(n*0.95) as usize];
+impl metadata::Stats for Chain {
+    const STATS: &'static [&'static str] = &[];
 
-        // format mean and quantiles into a summary to print out
+    fn stats(&self) -> Vec<f64> {
+        Vec::new()
+    }
+}
 
-        format!("s: {:.3} [{:.3}, {:.3}]\ntau: {:.3} [{:.3}, {:.3}]\nmu1: {:.3} [{:.3}, {:.3}]\nmu2: {:.3} [{:.3}, {:.3}]\ngamma1: {:.3} [{:.3}, {:.3}]\ngamma2: {:.3} [{:.3}, {:.3}]", s, s_5, s_95, tau, tau_5, tau_95, mu1, mu1_5, mu1_95, mu2, mu2_5, mu2_95, gamma1, gamma1_5, gamma1_95, gamma2, gamma2_5, gamma2_95)
+impl stan::Diagnostics for Chain {
+    const COLUMNS: &'static [&'static str] = &["accept_stat__"];
 
+    // every update is a draw from the full conditional
+    fn diagnostics(&self) -> Vec<f64> {
+        vec![1.0]
     }
-}
\ No newline at end of file
+}
+    
+impl Parameters {
+    /// `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`
+    pub fn to_vec(&self) -> Vec<f64> {
+        let mut v = vec![self.s, self.tau];
+        v.extend_from_slice(&self.mu);
+        v.extend_from_slice(&self.gamma);
+        v
+    }
+
+    /// Writes one row per draw, with the same columns as [`crate::mh::Parameters::save_to_csv`].
+    pub fn save_to_csv(ps: &[Parameters], filename: &str) -> Result<()> {
+        sink::save(ps, filename, sink::Format::Csv)
+    }
+
+    /// Mean and 5-95% interval of every parameter, with the ESS and MCSE columns of [`crate::diagnostics`].
+    pub fn summary(ps: &[Parameters]) -> String {
+        diagnostics::summary(ps)
+    }
+}
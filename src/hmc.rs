@@ -1,10 +1,21 @@
-use crate::data::Data;
+//! Hamiltonian Monte Carlo.
+
+#![allow(non_snake_case)]
+
+use crate::data::{self, Data};
+use crate::{checkpoint, diagnostics, metadata, sink, stan};
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
 use rand::distributions::Distribution;
 use statrs::distribution::Normal;
 use serde::{Serialize, Deserialize};
+use color_eyre::Result;
 
-#[derive(Debug, Clone)]
+// energy error beyond which a trajectory counts as divergent, as in Stan
+static MAX_ENERGY_ERROR: f64 = 1000.0;
+
+/// The state of the chain: position `q = (s, tau, mu, gamma)`, momentum `p` and the tuning.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Parameters {
     L: usize,
     dt: f64,
@@ -13,35 +24,66 @@ pub struct Parameters {
     q: Vec<f64>,
 }
 
+/// The position of a draw, as written to csv.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OutParameters {
     s: f64,
     tau: f64,
-    mu1: f64,
-    mu2: f64,
-    gamma1: f64,
-    gamma2: f64,
+    mu: Vec<f64>,
+    gamma: Vec<f64>,
+}
+
+/// Number of leapfrog steps, step size and the diagonal of the inverse mass matrix, unit masses when not given.
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub L: usize,
+    pub dt: f64,
+    pub m: Option<Vec<f64>>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self { L: 3, dt: 0.01, m: None }
+    }
 }
 
+/// A Hamiltonian Monte Carlo chain with a fixed number of leapfrog steps.
 pub struct Chain {
     data: Data,
     parameters: Parameters,
+    // acceptance probability and Hamiltonian of the last step, and whether its trajectory diverged
+    accept_prob: f64,
+    energy: f64,
+    divergent: bool,
 }
 
 impl Chain {
+    /// A chain with the default tuning, started at [`crate::mh::default_start`].
     pub fn new(data: Data) -> Self {
+        let start = crate::mh::default_start(data::n_traits(&data));
+        Self::with_start(data, &start)
+    }
+
+    /// `start` is `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`, e.g. the mode found by [`crate::map::run`].
+    pub fn with_start(data: Data, start: &[f64]) -> Self {
+        Self::with_tuning(data, start, Tuning::default())
+    }
+
+    /// Like [`Chain::with_start`], with the given leapfrog steps, step size and masses.
+    pub fn with_tuning(data: Data, start: &[f64], tuning: Tuning) -> Self {
         let parameters = Parameters {
-            L: 3,
-            dt: 0.01,
-            m: vec![1.0, 1.0, 1.0, 1.0, 1.0, 1.0],
-            p: vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-            q: vec![1.0, 0.5, 0.0, 0.0, 0.0, 0.0],
+            L: tuning.L,
+            dt: tuning.dt,
+            m: tuning.m.unwrap_or_else(|| vec![1.0; start.len()]),
+            p: vec![0.0; start.len()],
+            q: start.to_vec(),
         };
-        Self { data, parameters }
+        Self { data, parameters, accept_prob: 0.0, energy: f64::NAN, divergent: false }
     }
 
+    /// Runs `n_burnin` iterations, then returns the next `n_samples` states.
     pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
-        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
         for _ in 0..n_burnin {
             self.step(&mut rng);
         }
@@ -56,21 +98,29 @@ impl Chain {
         samples
     }
 
-    fn step(&mut self, rng: &mut StdRng) {
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
         let (q_new, p_new) = self.leapfrog_propose(rng);
 
-        let alpha: f64 = expH(&self.data, &self.parameters.m, &q_new, &p_new) / expH(&self.data, &self.parameters.m, &self.parameters.q, &self.parameters.p);
+        let h_new = H(&self.data, &self.parameters.m, &q_new, &p_new);
+        let h_old = H(&self.data, &self.parameters.m, &self.parameters.q, &self.parameters.p);
+        let alpha: f64 = (h_old - h_new).exp();
+
+        self.accept_prob = stan::accept_stat(alpha);
+        self.divergent = (h_new - h_old).is_nan() || h_new - h_old > MAX_ENERGY_ERROR;
+        self.energy = h_old;
 
         if rng.gen::<f64>() < alpha {
             self.parameters.q = q_new;
             self.parameters.p = p_new;
+            self.energy = h_new;
         }
     }
 
-    fn leapfrog_propose(&mut self, rng: &mut StdRng) -> (Vec<f64>, Vec<f64>) {
-        // use the fact that the mass matrix is diagonal
-        for i in 0..6 {
-            self.parameters.p[i] = Normal::new(0.0, self.parameters.m[i]).unwrap().sample(rng);
+    fn leapfrog_propose(&mut self, rng: &mut ChaCha12Rng) -> (Vec<f64>, Vec<f64>) {
+        // use the fact that the mass matrix is diagonal; m is its inverse, as in the kinetic
+        // energy m p^2/2 of H, so the momentum has variance 1/m
+        for i in 0..self.parameters.q.len() {
+            self.parameters.p[i] = Normal::new(0.0, 1.0 / self.parameters.m[i].sqrt()).unwrap().sample(rng);
         }
 
         let mut pn = self.parameters.p.clone();
@@ -79,7 +129,7 @@ impl Chain {
         for _ in 0..self.parameters.L {
             let du = dU(&self.data, &qn);
 
-            for i in 0..6 {
+            for i in 0..qn.len() {
                 pn[i] -= 0.5 * self.parameters.dt * du[i];
                 qn[i] += self.parameters.dt * self.parameters.m[i] * pn[i];
                 pn[i] -= 0.5 * self.parameters.dt * du[i];
@@ -90,96 +140,135 @@ impl Chain {
     }
 }
 
-fn expH(data: &Data, m: &Vec<f64>, q: &Vec<f64>,  p: &Vec<f64>) -> f64 {
+impl checkpoint::Resumable for Chain {
+    type State = Parameters;
+    type Draw = Parameters;
+
+    const NAME: &'static str = "hmc";
+
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
+        Chain::step(self, rng)
+    }
+
+    fn state(&self) -> Parameters {
+        self.parameters.clone()
+    }
+
+    fn restore(&mut self, state: &Parameters) {
+        self.parameters = state.clone();
+    }
+
+    fn draw(&self) -> Parameters {
+        self.parameters.clone()
+    }
+}
+
+impl metadata::Stats for Chain {
+    const STATS: &'static [&'static str] = &["accept_prob", "energy", "divergent"];
+
+    fn stats(&self) -> Vec<f64> {
+        vec![self.accept_prob, self.energy, self.divergent as u8 as f64]
+    }
+}
+
+impl stan::Diagnostics for Chain {
+    const COLUMNS: &'static [&'static str] = &["accept_stat__", "stepsize__", "treedepth__", "n_leapfrog__", "divergent__", "energy__"];
+
+    // the trajectory has a fixed length, reported as the depth of a NUTS tree with as many leapfrog steps
+    fn diagnostics(&self) -> Vec<f64> {
+        let L = self.parameters.L;
+        let treedepth = (usize::BITS - L.leading_zeros()) as f64;
+        vec![self.accept_prob, self.parameters.dt, treedepth, L as f64, self.divergent as u8 as f64, self.energy]
+    }
+}
+
+/// The Hamiltonian, potential plus kinetic energy, at position `q` and momentum `p`.
+pub fn H(data: &Data, m: &[f64], q: &[f64],  p: &[f64]) -> f64 {
     let u = U(data, q);
     let mut v: f64 = 0.0;
     for (mi, pi) in m.iter().zip(p.iter()) {
         v += mi * pi * pi;
     }
-    let H = - v / 2.0 - u;
-    H.exp()
+    v / 2.0 + u
 }
 
-fn U(data: &Data, q: &Vec<f64>) -> f64 {
+/// Potential energy, i.e. the negative log posterior of `q = (s, tau, mu, gamma)`, [`crate::mh::log_posterior`] without its bounds on `s` and `tau`.
+pub fn U(data: &Data, q: &[f64]) -> f64 {
+    let D = data::n_traits(data);
     let N = data.len() as f64;
-    let mut u = N*(6.2831_f64.ln()) + (N+1.)*q[0].ln();
+    let n = D as f64 * N / 2.;
+    let mut u = n*(std::f64::consts::TAU.ln()) + (n+1.)*q[0].ln();
+    let (mu, gamma) = (&q[2..2 + D], &q[2 + D..2 + 2*D]);
 
     for r in data.iter() {
-        match r.group {
-            1 => { 
-                let t = (r.x1 - q[2]).powi(2) + (r.x2 - q[3]).powi(2); 
-                u += t/(2.*q[0]);
-            },
-            2 => {
-                let t = (r.x1 - q[4]).powi(2) + (r.x2 - q[5]).powi(2); 
-                u += t/(2.*q[0]);
-            },
-            3 => {
-                let t = (r.x1 - q[2]*0.5 - q[4]*0.5).powi(2) + (r.x2 - q[3]*0.5 - q[5]*0.5).powi(2); 
-                u += t/(2.*q[0]);
-            },
-            4 => {
-                let t = (r.x1 - q[2]*q[1] - q[4]*(1. - q[1])).powi(2) + (r.x2  - q[3]*q[1] - q[5]*(1. - q[1])).powi(2); 
-                u += t/(2.*q[0]);
-            },
+        let t: f64 = match r.group {
+            1 => r.x.iter().zip(mu).map(|(x, m)| (x - m).powi(2)).sum(),
+            2 => r.x.iter().zip(gamma).map(|(x, g)| (x - g).powi(2)).sum(),
+            3 => r.x.iter().zip(mu.iter().zip(gamma)).map(|(x, (m, g))| (x - m*0.5 - g*0.5).powi(2)).sum(),
+            4 => r.x.iter().zip(mu.iter().zip(gamma)).map(|(x, (m, g))| (x - m*q[1] - g*(1. - q[1])).powi(2)).sum(),
             _ => unreachable!(),
-        }
+        };
+        u += t/(2.*q[0]);
     }
 
     u
 }
 
-fn dU(data: &Data, q: &Vec<f64>) -> Vec<f64> {
+/// Gradient of [`U`] with respect to `q`.
+pub fn dU(data: &Data, q: &[f64]) -> Vec<f64> {
+    let D = data::n_traits(data);
     let N = data.len() as f64;
+    let n = D as f64 * N / 2.;
 
-    let mut du = vec![0.; 6];
+    let mut du = vec![0.; q.len()];
 
-    du[0] += (N+1.)/q[0];
+    du[0] += (n+1.)/q[0];
 
+    // mu_k is q[2 + k] and gamma_k is q[2 + D + k]
     for row in data.iter() {
         match row.group {
             1 => {
-                let t0 = (row.x1 - q[2]).powi(2) + (row.x2 - q[3]).powi(2);
+                let t0: f64 = (0..D).map(|k| (row.x[k] - q[2+k]).powi(2)).sum();
                 du[0] -= t0/(2.*q[0].powi(2));
 
-                du[2] -= (row.x1 - q[2])/q[0];
-                du[3] -= (row.x2 - q[3])/q[0];
+                for k in 0..D {
+                    du[2+k] -= (row.x[k] - q[2+k])/q[0];
+                }
             },
             2 => {
-                let t0 = (row.x1 - q[4]).powi(2) + (row.x2 - q[5]).powi(2);
+                let t0: f64 = (0..D).map(|k| (row.x[k] - q[2+D+k]).powi(2)).sum();
                 du[0] -= t0/(2.*q[0].powi(2));
 
-                du[4] -= (row.x1 - q[4])/q[0];
-                du[5] -= (row.x2 - q[5])/q[0];
+                for k in 0..D {
+                    du[2+D+k] -= (row.x[k] - q[2+D+k])/q[0];
+                }
             },
             3 => {
-                let t0 = (row.x1 - q[2]*0.5 - q[4]*0.5).powi(2) + (row.x2 - q[3]*0.5 - q[5]*0.5).powi(2);
+                let t0: f64 = (0..D).map(|k| (row.x[k] - q[2+k]*0.5 - q[2+D+k]*0.5).powi(2)).sum();
                 du[0] -= t0/(2.*q[0].powi(2));
 
-                let t2 = (row.x1-q[2]*0.5-q[4]*0.5)/(2.*q[0]);
-                let t3 = (row.x2-q[3]*0.5-q[5]*0.5)/(2.*q[0]);
-
-                du[2] -= t2;
-                du[3] -= t3;
-                du[4] -= t2;
-                du[5] -= t3;
-
+                for k in 0..D {
+                    let t = (row.x[k]-q[2+k]*0.5-q[2+D+k]*0.5)/(2.*q[0]);
+                    du[2+k] -= t;
+                    du[2+D+k] -= t;
+                }
             },
             4 => {
-                let t0 = (row.x1 - q[2]*q[1] - q[4]*(1. - q[1])).powi(2) + (row.x2 - q[3]*q[1] - q[5]*(1. - q[1])).powi(2);
+                let t0: f64 = (0..D).map(|k| (row.x[k] - q[2+k]*q[1] - q[2+D+k]*(1. - q[1])).powi(2)).sum();
                 du[0] -= t0/(2.*q[0].powi(2));
 
-                let t1 = (row.x1 - q[2]*q[1] - q[4]*(1. - q[1]))*(q[4] - q[2]) + (row.x2 - q[3]*q[1] - q[5]*(1. - q[1]))*(q[5] - q[3]); 
+                let t1: f64 = (0..D).map(|k| (row.x[k] - q[2+k]*q[1] - q[2+D+k]*(1. - q[1]))*(q[2+D+k] - q[2+k])).sum();
                 du[1] += t1/q[0];
-                
-                let t2 = (row.x1 - q[2]*q[1] - q[4]*(1.-q[1]))/q[0];
-                let t3 = (row.x2 - q[3]*q[1] - q[5]*(1.-q[1]))/q[0];
-
-                du[2] -= q[1]*t2;
-                du[3] -= q[1]*t3;
-                du[4] -= (1.-q[1])*t2;
-                du[5] -= (1.-q[1])*t3;
 
+                for k in 0..D {
+                    let t = (row.x[k] - q[2+k]*q[1] - q[2+D+k]*(1.-q[1]))/q[0];
+                    du[2+k] -= q[1]*t;
+                    du[2+D+k] -= (1.-q[1])*t;
+                }
             },
             _ => unreachable!(),
         }
@@ -190,120 +279,36 @@ fn dU(data: &Data, q: &Vec<f64>) -> Vec<f64> {
 
 
 impl OutParameters {
+    /// The position of `p`.
     pub fn from_parameters(p: &Parameters) -> OutParameters {
+        let D = p.n_traits();
         OutParameters {
-            s: p.s(),
-            tau: p.tau(),
-            mu1: p.mu1(),
-            mu2: p.mu2(),
-            gamma1: p.gamma1(),
-            gamma2: p.gamma2(),
-
+            s: p.q[0],
+            tau: p.q[1],
+            mu: p.q[2..2 + D].to_vec(),
+            gamma: p.q[2 + D..2 + 2*D].to_vec(),
         }
     }
 
-    pub fn save_to_csv(ps: &Vec<Parameters>, filename: &str) {
-        let mut wtr = csv::Writer::from_path(filename).unwrap();
-
-        for p in ps.iter().map(|p| OutParameters::from_parameters(p)) {
-            wtr.serialize(p).unwrap();
-        }
+    /// Writes the positions of the draws, with the same columns as [`crate::mh::Parameters::save_to_csv`].
+    pub fn save_to_csv(ps: &[Parameters], filename: &str) -> Result<()> {
+        sink::save(ps, filename, sink::Format::Csv)
     }
 }
 
 impl Parameters {
-    fn s(&self) -> f64 {
-        self.q[0]
+    /// Number of traits.
+    pub fn n_traits(&self) -> usize {
+        data::n_traits_of(self.q.len())
     }
 
-    fn tau(&self) -> f64 {
-        self.q[1]
+    /// The position `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`.
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.q.clone()
     }
 
-    fn mu1(&self) -> f64 {
-        self.q[2]
+    /// Mean and 5-95% interval of every parameter, with the ESS and MCSE columns of [`crate::diagnostics`].
+    pub fn summary(ps: &[Parameters]) -> String {
+        diagnostics::summary(ps)
     }
-
-    fn mu2(&self) -> f64 {
-        self.q[3]
-    }
-
-    fn gamma1(&self) -> f64 {
-        self.q[4]
-    }
-
-    fn gamma2(&self) -> f64 {
-        self.q[5]
-    }
-
-    pub fn summary(ps: &Vec<Parameters>) -> String {
-        // compute mean of each parameter
-        let n = ps.len() as f64;
-
-        let mut s = 0.0;
-        let mut tau = 0.0;
-        let mut mu1 = 0.0;
-        let mut mu2 = 0.0;
-        let mut gamma1 = 0.0;
-        let mut gamma2 = 0.0;
-
-        for p in ps {
-            s += p.s();
-            tau += p.tau();
-            mu1 += p.mu1();
-            mu2 += p.mu2();
-            gamma1 += p.gamma1();
-            gamma2 += p.gamma2();
-        }
-        
-        s /= n;
-        tau /= n;
-        mu1 /= n;
-        mu2 /= n;
-        gamma1 /= n;
-        gamma2 /= n;
-
-        // compute 5th and 95th quantiles of each parameter
-        let mut s_vec = Vec::new();
-        let mut tau_vec = Vec::new();
-        let mut mu1_vec = Vec::new();
-        let mut mu2_vec = Vec::new();
-        let mut gamma1_vec = Vec::new();
-        let mut gamma2_vec = Vec::new();
-
-        for p in ps {
-            s_vec.push(p.s());
-            tau_vec.push(p.tau());
-            mu1_vec.push(p.mu1());
-            mu2_vec.push(p.mu2());
-            gamma1_vec.push(p.gamma1());
-            gamma2_vec.push(p.gamma2());
-        }
-
-        s_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        tau_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-        let s_5 = s_vec[(n*0.05) as usize];
-        let tau_5 = tau_vec[(n*0.05) as usize];
-        let mu1_5 = mu1_vec[(n*0.05) as usize];
-        let mu2_5 = mu2_vec[(n*0.05) as usize];
-        let gamma1_5 = gamma1_vec[(n*0.05) as usize];
-        let gamma2_5 = gamma2_vec[(n*0.05) as usize];
-
-        let s_95 = s_vec[(n*0.95) as usize];
-        let tau_95 = tau_vec[(n*0.95) as usize];
-        let mu1_95 = mu1_vec[(n*0.95) as usize];
-        let mu2_95 = mu2_vec[(n*0.95) as usize];
-        let gamma1_95 = gamma1_vec[(n*0.95) as usize];
-        let gamma2_95 = gamma2_vec[(n*0.95) as usize];
-
-        // format mean and quantiles into a summary to print out
-
-        format!("s: {:.3} [{:.3}, {:.3}]\ntau: {:.3} [{:.3}, {:.3}]\nmu1: {:.3} [{:.3}, {:.3}]\nmu2: {:.3} [{:.3}, {:.3}]\ngamma1: {:.3} [{:.3}, {:.3}]\ngamma2: {:.3} [{:.3}, {:.3}]", s, s_5, s_95, tau, tau_5, tau_95, mu1, mu1_5, mu1_95, mu2, mu2_5, mu2_95, gamma1, gamma1_5, gamma1_95, gamma2, gamma2_5, gamma2_95)
-
-    }
-}
\ No newline at end of file
+}
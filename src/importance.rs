@@ -1,112 +1,176 @@
-use crate::data::{Data, Row};
+//! Importance sampling of the posterior mean.
+
+use crate::data::{self, Data, Row};
 use rand::prelude::*;
 use rand::distributions::Distribution;
 use statrs::distribution::{Uniform, Normal, Exp};
 use serde::{Serialize, Deserialize};
 
+/// Rate of the exponential proposal of `s` in [`Proposal::for_data`].
+pub static S_RATE: f64 = 12.0;
+/// Standard deviation of the normal proposals of the means in [`Proposal::for_data`].
+pub static PROPOSAL_SD: f64 = 1.5;
+
+/// A draw of `(s, tau, mu, gamma)` with one `mu` and `gamma` per trait, or the weighted mean returned by [`run`].
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Parameters {
     s: f64,
     tau: f64,
-    mu1: f64,
-    mu2: f64,
-    gamma1: f64,
-    gamma2: f64,
+    mu: Vec<f64>,
+    gamma: Vec<f64>,
+}
+
+/// Independent proposal: `s ~ Exp(s_rate)`, `tau ~ U(0, 1)` and normals with the given
+/// means `(mu1, ..., muD, gamma1, ..., gammaD)` and a common standard deviation.
+#[derive(Debug, Clone)]
+pub struct Proposal {
+    pub s_rate: f64,
+    pub mean: Vec<f64>,
+    pub sd: f64,
 }
 
+
+/// Self-normalised importance sampling estimate of the posterior mean from `niter` draws of [`Proposal::for_data`].
 pub fn run(data: Data, niter: usize, seed: usize) -> Parameters {
+    let proposal = Proposal::for_data(&data);
+    run_with_proposal(data, &proposal, niter, seed)
+}
+
+/// Like [`run`], with the given proposal, which must have a mean for every `mu` and `gamma`.
+pub fn run_with_proposal(data: Data, proposal: &Proposal, niter: usize, seed: usize) -> Parameters {
+    assert_eq!(proposal.mean.len(), 2 * data::n_traits(&data), "need a proposal mean for every mu and gamma");
+
     // inits
     let mut rng = StdRng::seed_from_u64(seed as u64);
     let mut samples: Vec<Parameters> = Vec::with_capacity(niter);
-    let mut weights: Vec<f64> = Vec::with_capacity(niter);
+    let mut log_weights: Vec<f64> = Vec::with_capacity(niter);
 
     // for each iter 
     for _ in 0..niter {
         // propose
-        let p = generate_sample(&mut rng);
+        let p = proposal.sample(&mut rng);
 
-        // compute weight
-        let w = likelihood(&data, &p)/trial_likelihood(&p);
+        // compute the log of the weight, which underflows as a product with many traits
+        let log_w = log_posterior(&data, &p) - proposal.log_density(&p);
 
         // save to arrays
         samples.push(p);
-        weights.push(w);
+        log_weights.push(log_w);
     }
 
+    // scaled by the largest weight, which cancels in the self-normalised mean
+    let max = log_weights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = log_weights.iter().map(|l| (l - max).exp()).collect();
     let wsum: f64 = weights.iter().sum();
-    
-    let sigma_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.s*w).sum::<f64>()/wsum;
-    let tau_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.tau*w).sum::<f64>()/wsum;
-    let mu1_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.mu1*w).sum::<f64>()/wsum;
-    let mu2_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.mu2*w).sum::<f64>()/wsum;
-    let gamma1_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.gamma1*w).sum::<f64>()/wsum;
-    let gamma2_mean: f64 = samples.iter().zip(weights.iter()).map(|(p, w)| p.gamma2*w).sum::<f64>()/wsum;
+
+    let weighted_mean = |value: &dyn Fn(&Parameters) -> f64| samples.iter().zip(weights.iter()).map(|(p, w)| value(p)*w).sum::<f64>()/wsum;
+    let d = data::n_traits(&data);
 
     // save and print
 
     Parameters {
-        s: sigma_mean,
-        tau: tau_mean,
-        mu1: mu1_mean,
-        mu2: mu2_mean,
-        gamma1: gamma1_mean,
-        gamma2: gamma2_mean,
+        s: weighted_mean(&|p| p.s),
+        tau: weighted_mean(&|p| p.tau),
+        mu: (0..d).map(|k| weighted_mean(&|p| p.mu[k])).collect(),
+        gamma: (0..d).map(|k| weighted_mean(&|p| p.gamma[k])).collect(),
     }
 }
 
-fn generate_sample(rng: &mut StdRng) -> Parameters {
-    let tau = Uniform::new(0.0, 1.0).unwrap().sample(rng);
-    let s = Exp::new(12.0).unwrap().sample(rng);
-    let mu1 = Normal::new(-1.5, 1.5).unwrap().sample(rng);
-    let mu2 = Normal::new(-0.5, 1.5).unwrap().sample(rng);
-    let gamma1 = Normal::new(-0.3, 1.5).unwrap().sample(rng);
-    let gamma2 = Normal::new(0.3, 1.5).unwrap().sample(rng);
-
-    Parameters {
-        s,
-        tau,
-        mu1,
-        mu2,
-        gamma1,
-        gamma2,
-    }
+/// A draw from [`Proposal::for_data`].
+pub fn generate_sample(data: &Data, rng: &mut StdRng) -> Parameters {
+    Proposal::for_data(data).sample(rng)
 }
 
-fn likelihood(data: &Data, p: &Parameters) -> f64 {
-    data.iter().map(|row| row_likelihood(&row, p)).product()
+/// Normalised log density of the distribution sampled by [`generate_sample`].
+pub fn log_proposal_density(data: &Data, p: &Parameters) -> f64 {
+    Proposal::for_data(data).log_density(p)
 }
 
-fn trial_likelihood(p: &Parameters) -> f64 {
-    pnorm(p.mu1, -1.5,2.25)*pnorm(p.mu2, -0.5,2.25 )*pnorm(p.gamma1, -0.3, 2.25)*pnorm(p.gamma2, 0.3, 2.25)*pexp(p.s, 12.0)
-}
+impl Proposal {
+    /// The proposal with [`S_RATE`], [`PROPOSAL_SD`] and the normals centred at the means of groups 1 and 2.
+    pub fn for_data(data: &Data) -> Self {
+        let mut mean = data::group_mean(data, 1);
+        mean.extend(data::group_mean(data, 2));
+        Self { s_rate: S_RATE, mean, sd: PROPOSAL_SD }
+    }
 
-fn row_likelihood(r: &Row, p: &Parameters) -> f64 {
-    match r.group {
-        1 => { pnorm(r.x1, p.mu1, p.s) * pnorm(r.x2, p.mu2, p.s)/p.s },
-        2 => { pnorm(r.x1, p.gamma1, p.s) * pnorm(r.x2, p.gamma2, p.s)/p.s   },
-        3 => { pnorm(r.x1, 0.5*p.mu1 + 0.5*p.gamma1, p.s) * pnorm(r.x2, 0.5*p.mu2 + 0.5*p.gamma2, p.s)/p.s  },
-        4 => { pnorm(r.x1, p.tau*p.mu1 + (1. - p.tau)*p.gamma1, p.s) * pnorm(r.x2, p.tau*p.mu2 + (1. - p.tau)*p.gamma2, p.s)/p.s  },
-        _ => unreachable!(),
+    /// A draw from the proposal.
+    pub fn sample(&self, rng: &mut StdRng) -> Parameters {
+        let d = self.mean.len() / 2;
+        let tau = Uniform::new(0.0, 1.0).unwrap().sample(rng);
+        let s = Exp::new(self.s_rate).unwrap().sample(rng);
+        let mu = self.mean[..d].iter().map(|m| Normal::new(*m, self.sd).unwrap().sample(rng)).collect();
+        let gamma = self.mean[d..].iter().map(|m| Normal::new(*m, self.sd).unwrap().sample(rng)).collect();
+
+        Parameters {
+            s,
+            tau,
+            mu,
+            gamma,
+        }
+    }
+
+    /// Normalised log density of the proposal.
+    pub fn log_density(&self, p: &Parameters) -> f64 {
+        if p.s <= 0.0 || p.tau <= 0.0 || p.tau >= 1.0 {
+            return f64::NEG_INFINITY;
+        }
+        let ln_norm = |x: f64, mu: f64| -0.5*((x - mu)/self.sd).powi(2) - self.sd.ln() - 0.5*std::f64::consts::TAU.ln();
+
+        let mut l = self.s_rate.ln() - self.s_rate*p.s;
+        for (x, m) in p.mu.iter().chain(p.gamma.iter()).zip(self.mean.iter()) {
+            l += ln_norm(*x, *m);
+        }
+        l
     }
 }
 
-// normal likelihood  
-fn pnorm(x:f64, mu: f64, s: f64) -> f64 {
-    (-(x - mu).powi(2)/2.0/s).exp()/s.sqrt()
+// `mh::log_posterior` up to a constant, at a draw of the proposal
+fn log_posterior(data: &Data, p: &Parameters) -> f64 {
+    data.iter().map(|row| log_row_likelihood(row, p)).sum::<f64>() - p.s.ln()
+}
+
+fn log_row_likelihood(r: &Row, p: &Parameters) -> f64 {
+    let mean = |k: usize| match r.group {
+        1 => p.mu[k],
+        2 => p.gamma[k],
+        3 => 0.5*p.mu[k] + 0.5*p.gamma[k],
+        4 => p.tau*p.mu[k] + (1. - p.tau)*p.gamma[k],
+        _ => unreachable!(),
+    };
+    r.x.iter().enumerate().map(|(k, x)| lnorm(*x, mean(k), p.s)).sum::<f64>()
 }
 
-// exponential likelihood
-fn pexp(x: f64, s: f64) -> f64 {
-    (-x/s).exp()/s
+// log of the normal likelihood
+fn lnorm(x: f64, mu: f64, s: f64) -> f64 {
+    -(x - mu).powi(2)/2.0/s - 0.5*s.ln()
 }
 
 impl Parameters {
+    /// Same parameter order as [`crate::mh::Parameters::from_slice`].
+    pub fn from_slice(q: &[f64]) -> Parameters {
+        let d = data::n_traits_of(q.len());
+        Parameters {
+            s: q[0],
+            tau: q[1],
+            mu: q[2..2 + d].to_vec(),
+            gamma: q[2 + d..2 + 2 * d].to_vec(),
+        }
+    }
+
+    /// `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`
+    pub fn to_vec(&self) -> Vec<f64> {
+        let mut v = vec![self.s, self.tau];
+        v.extend_from_slice(&self.mu);
+        v.extend_from_slice(&self.gamma);
+        v
+    }
+
+    /// Prints one line per parameter.
     pub fn print_values(&self) {
-        println!("s: {}", self.s);
-        println!("tau: {}", self.tau);
-        println!("mu1: {}", self.mu1);
-        println!("mu2: {}", self.mu2);
-        println!("gamma1: {}", self.gamma1);
-        println!("gamma2: {}", self.gamma2);
+        let names = data::parameter_names(self.mu.len());
+        for (name, value) in names.iter().zip(self.to_vec()) {
+            println!("{}: {}", name, value);
+        }
     }
-}
\ No newline at end of file
+}
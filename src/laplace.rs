@@ -0,0 +1,70 @@
+//! The Laplace approximation of the posterior and of the evidence.
+//!
+//! The posterior of `u = (log s, logit tau, mu, gamma)` is approximated by the normal
+//! `N(mode, H^-1)`, where the mode and the hessian H are those of the density of u, i.e.
+//! including the log jacobian. Like [`crate::map`], this uses the posterior of `hmc::U`, which is
+//! [`crate::mh::log_posterior`].
+
+use crate::data::Data;
+use crate::linalg::{self, Matrix};
+use crate::map;
+use crate::mh::Parameters;
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::Normal;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+
+/// The normal approximation `N(mode, covariance)` in the unconstrained space.
+pub struct Output {
+    pub mode: Vec<f64>,
+    /// The inverse of the hessian at the mode.
+    pub covariance: Matrix,
+    /// Log of the normalising constant of [`crate::mh::log_posterior`], approximated at the mode.
+    pub log_evidence: f64,
+    chol: Matrix,
+}
+
+/// Starts the search from the mode found by [`map::run`], which is close;
+/// fails when the hessian at the mode is not positive definite, e.g. at the boundary of the support.
+pub fn run(data: &Data, map_output: &map::Output) -> Result<Output> {
+    let objective = |u: &[f64]| map::neg_log_posterior(data, u, true);
+
+    let (mode, _, _) = map::lbfgs(objective, map_output.mode_unconstrained.clone());
+
+    let hessian = map::hessian(|x| objective(x).1, &mode);
+    let covariance = linalg::inverse_spd(&hessian).ok_or_else(|| eyre!("the hessian at the posterior mode is not positive definite"))?;
+    let chol = linalg::cholesky(&covariance).ok_or_else(|| eyre!("the covariance of the Laplace approximation is not positive definite"))?;
+
+    // log Z = log p(u*) + d/2 log(2 pi) - 1/2 log |H|, with log |H| = -log |H^-1|
+    let d = mode.len() as f64;
+    let log_evidence = -objective(&mode).0 + 0.5 * d * std::f64::consts::TAU.ln() + 0.5 * linalg::log_det_cholesky(&chol);
+
+    Ok(Output {
+        mode,
+        covariance,
+        log_evidence,
+        chol,
+    })
+}
+
+impl Output {
+    /// `n` draws from the approximation, mapped back to the original parameters.
+    pub fn sample(&self, n: usize, seed: u64) -> Vec<Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let normal = Normal::new(0.0, 1.0).unwrap();
+
+        (0..n)
+            .map(|_| {
+                let z: Vec<f64> = (0..self.mode.len()).map(|_| normal.sample(&mut rng)).collect();
+                let u: Vec<f64> = linalg::mat_vec(&self.chol, &z).iter().zip(self.mode.iter()).map(|(a, b)| a + b).collect();
+                Parameters::from_unconstrained(&u).0
+            })
+            .collect()
+    }
+
+    /// Approximate posterior standard deviations of `u`.
+    pub fn std_devs(&self) -> Vec<f64> {
+        (0..self.mode.len()).map(|i| self.covariance[i][i].sqrt()).collect()
+    }
+}
@@ -0,0 +1,53 @@
+//! Bayesian inference for a linear mixing model of any number of traits.
+//!
+//! Every row of the data has a group and a vector of `D` trait values, e.g. `x1` and `x2`.
+//! The mean of group 1 is `mu`, of group 2 `gamma`, of group 3 `(mu + gamma)/2` and of
+//! group 4 `tau mu + (1 - tau) gamma`, where `mu` and `gamma` are `D`-vectors, with a
+//! common variance `s` for all traits. Every sampler returns draws of
+//! `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`, in that order.
+//!
+//! The main entry points are
+//!
+//! - [`data`]: reading the data,
+//! - [`mh`]: random-walk Metropolis-Hastings,
+//! - [`hmc`]: Hamiltonian Monte Carlo,
+//! - [`gibbs`]: the Gibbs sampler,
+//! - [`importance`]: importance sampling of the posterior mean.
+//!
+//! The other modules hold the remaining samplers, the evidence estimators and the
+//! convergence diagnostics; the command-line tool that runs them is the binary of this crate.
+//!
+//! ```no_run
+//! use mcmc_project::{data, mh};
+//!
+//! let data = data::load_data("data.csv")?;
+//! let samples = mh::Chain::new(data).run(1000, 8000, 42);
+//! println!("{}", mh::Parameters::summary(&samples));
+//! # Ok::<(), color_eyre::Report>(())
+//! ```
+
+pub mod data;
+pub mod mh;
+pub mod hmc;
+pub mod importance;
+pub mod gibbs;
+pub mod ensemble;
+pub mod demc;
+pub mod tempering;
+pub mod smc;
+pub mod ais;
+pub mod nested;
+pub mod linalg;
+pub mod bridge;
+pub mod map;
+pub mod laplace;
+pub mod advi;
+pub mod em;
+pub mod multichain;
+pub mod diagnostics;
+pub mod config;
+pub mod checkpoint;
+pub mod sink;
+pub mod stan;
+pub mod metadata;
+pub mod trace;
@@ -0,0 +1,115 @@
+//! Small dense linear algebra for the (2 + 2D)-dimensional problems in this crate.
+//!
+//! Matrices are stored as `Vec<Vec<f64>>` in row-major order.
+
+/// A dense matrix, one `Vec` per row.
+pub type Matrix = Vec<Vec<f64>>;
+
+/// The `n` x `n` identity matrix.
+pub fn identity(n: usize) -> Matrix {
+    (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect()
+}
+
+/// The product `a x`.
+pub fn mat_vec(a: &Matrix, x: &[f64]) -> Vec<f64> {
+    a.iter().map(|row| dot(row, x)).collect()
+}
+
+/// Inner product of `x` and `y`.
+pub fn dot(x: &[f64], y: &[f64]) -> f64 {
+    x.iter().zip(y.iter()).map(|(a, b)| a * b).sum()
+}
+
+/// Sample mean and covariance of the rows of `xs`.
+pub fn mean_cov(xs: &[Vec<f64>]) -> (Vec<f64>, Matrix) {
+    let n = xs.len() as f64;
+    let d = xs[0].len();
+
+    let mean: Vec<f64> = (0..d).map(|j| xs.iter().map(|x| x[j]).sum::<f64>() / n).collect();
+
+    let mut cov = vec![vec![0.0; d]; d];
+    for x in xs.iter() {
+        for i in 0..d {
+            for j in 0..d {
+                cov[i][j] += (x[i] - mean[i]) * (x[j] - mean[j]);
+            }
+        }
+    }
+    for row in cov.iter_mut() {
+        for c in row.iter_mut() {
+            *c /= n - 1.0;
+        }
+    }
+
+    (mean, cov)
+}
+
+/// Lower triangular L with `a = L L^T`, or `None` if `a` is not positive definite.
+pub fn cholesky(a: &Matrix) -> Option<Matrix> {
+    let n = a.len();
+    let mut l = vec![vec![0.0; n]; n];
+
+    for i in 0..n {
+        for j in 0..=i {
+            let s: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let d = a[i][i] - s;
+                if d <= 0.0 || !d.is_finite() {
+                    return None;
+                }
+                l[i][j] = d.sqrt();
+            } else {
+                l[i][j] = (a[i][j] - s) / l[j][j];
+            }
+        }
+    }
+
+    Some(l)
+}
+
+/// `log |a|` from the Cholesky factor of `a`.
+pub fn log_det_cholesky(l: &Matrix) -> f64 {
+    2.0 * (0..l.len()).map(|i| l[i][i].ln()).sum::<f64>()
+}
+
+/// Solves `L y = b` for lower triangular L.
+pub fn forward_solve(l: &Matrix, b: &[f64]) -> Vec<f64> {
+    let n = b.len();
+    let mut y = vec![0.0; n];
+    for i in 0..n {
+        let s: f64 = (0..i).map(|k| l[i][k] * y[k]).sum();
+        y[i] = (b[i] - s) / l[i][i];
+    }
+    y
+}
+
+/// Solves `L^T x = y` for lower triangular L.
+pub fn backward_solve(l: &Matrix, y: &[f64]) -> Vec<f64> {
+    let n = y.len();
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        let s: f64 = (i + 1..n).map(|k| l[k][i] * x[k]).sum();
+        x[i] = (y[i] - s) / l[i][i];
+    }
+    x
+}
+
+/// Inverse of a symmetric positive definite matrix.
+pub fn inverse_spd(a: &Matrix) -> Option<Matrix> {
+    let l = cholesky(a)?;
+    let n = a.len();
+
+    // the inverse is symmetric, so its columns can be used as rows
+    let inv = identity(n).iter()
+        .map(|e| backward_solve(&l, &forward_solve(&l, e)))
+        .collect();
+
+    Some(inv)
+}
+
+/// Log density of `N(mean, L L^T)` at `x`.
+pub fn mvn_log_density(x: &[f64], mean: &[f64], l: &Matrix) -> f64 {
+    let diff: Vec<f64> = x.iter().zip(mean.iter()).map(|(a, b)| a - b).collect();
+    let z = forward_solve(l, &diff);
+    -0.5 * dot(&z, &z) - 0.5 * log_det_cholesky(l) - 0.5 * x.len() as f64 * std::f64::consts::TAU.ln()
+}
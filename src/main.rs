@@ -1,68 +1,249 @@
+use clap::{Args, Parser, Subcommand};
+use color_eyre::eyre::bail;
 use color_eyre::Result;
+use mcmc_project::config::Config;
+use mcmc_project::{multichain, sink, trace};
 
-pub mod data;
-pub mod mh;
-pub mod hmc;
-pub mod importance;
-pub mod gibbs;
+mod run;
 
-fn main() -> Result<()>{
+#[derive(Parser)]
+#[command(about = "Bayesian inference for the linear mixing model of any number of traits")]
+struct Cli {
+    /// TOML or JSON file with the run configuration; options given on the command line take precedence
+    #[arg(short, long, global = true)]
+    config: Option<String>,
 
-    println!("loading data...");
+    // without a subcommand everything is run, as `all`
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let data = data::load_data()?;
+#[derive(Subcommand)]
+enum Command {
+    /// Random-walk Metropolis-Hastings
+    Mh(SamplerArgs),
+    /// Hamiltonian Monte Carlo
+    Hmc(SamplerArgs),
+    /// Gibbs sampler
+    Gibbs(SamplerArgs),
+    /// Importance sampling estimate of the posterior mean
+    Importance(ImportanceArgs),
+    /// Every method, one after the other
+    All(SamplerArgs),
+    /// Continue a checkpointed chain
+    Resume(ResumeArgs),
+    /// Convert between CSV files of draws and a binary trace
+    Convert(ConvertArgs),
+}
 
-    println!("running Metropolis-Hastings...");
+// the defaults of the options are those of `Config::default`
+#[derive(Args, Default)]
+struct DataArgs {
+    /// CSV file with a group column and one column per trait [default: data.csv]
+    #[arg(short, long)]
+    input: Option<String>,
 
-    let mut mh_chain = mh::Chain::new(data.clone());
+    /// Directory the results and the resolved configuration are written to [default: .]
+    #[arg(short, long)]
+    output: Option<String>,
 
-    let mh_samples = mh_chain.run(1000, 8000, 42);
+    /// Seed of the random number generator [default: 42]
+    #[arg(long)]
+    seed: Option<u64>,
+}
 
-    println!("MH results:");
+#[derive(Args, Default)]
+struct ChainArgs {
+    /// Number of burn-in iterations [default: 1000]
+    #[arg(long)]
+    burnin: Option<usize>,
 
-    println!("{}", mh::Parameters::summary(&mh_samples));
+    /// Number of iterations after the burn-in [default: 8000]
+    #[arg(long)]
+    samples: Option<usize>,
 
-    println!("saving the samples to file 'mh_samples.csv'...");
+    /// Keep every n-th draw [default: 1]
+    #[arg(long)]
+    thin: Option<usize>,
 
-    mh::Parameters::save_to_csv(&mh_samples, "mh_samples.csv");
+    /// Number of chains started from dispersed points [default: 1, 4 for `all`]
+    #[arg(long)]
+    chains: Option<usize>,
 
-    println!("running Hamiltonian Monte Carlo...");
+    /// Checkpoint file of a single chain, to continue it with `resume` after a crash
+    #[arg(long)]
+    checkpoint: Option<String>,
 
-    let mut hmc_chain = hmc::Chain::new(data.clone());
+    /// Iterations between checkpoints [default: 1000]
+    #[arg(long)]
+    checkpoint_every: Option<usize>,
 
-    let hmc_samples = hmc_chain.run(1000, 8000, 42);
+    /// Format of the samples: csv, jsonl, binary or stan (CmdStan CSV, single chains only) [default: csv]
+    #[arg(long)]
+    output_format: Option<sink::Format>,
 
-    println!("HMC results:");
+    /// Draws between flushes of a streamed chain [default: 1000]
+    #[arg(long)]
+    flush_every: Option<usize>,
 
-    println!("{}", hmc::Parameters::summary(&hmc_samples));
+    /// Only write the draws, without the summaries; by default every draw is also kept in memory for the summaries, so memory grows with the number of draws
+    #[arg(long)]
+    no_summary: bool,
 
-    println!("saving the samples to file 'hmc_samples.csv'...");
+    /// Write the chain id, iteration, log posterior, log-likelihood and sampler statistics with every draw
+    #[arg(long)]
+    metadata: bool,
+}
 
-    hmc::OutParameters::save_to_csv(&hmc_samples, "hmc_samples.csv");
+#[derive(Args, Default)]
+struct SamplerArgs {
+    #[command(flatten)]
+    data: DataArgs,
 
-    println!("russing importance sampling...");
+    #[command(flatten)]
+    chain: ChainArgs,
+}
 
-    let importance_samples = importance::run(data.clone(), 10000, 42);
+#[derive(Args)]
+struct ResumeArgs {
+    /// Checkpoint file written by `mh`, `hmc` or `gibbs` with --checkpoint
+    checkpoint: String,
 
-    println!("importance sampling results:");
+    /// CSV file with the data the chain was started on [default: data.csv]
+    #[arg(short, long)]
+    input: Option<String>,
 
-    importance_samples.print_values();
+    /// Directory the resolved configuration is written to; the samples are appended to the file the chain was writing [default: .]
+    #[arg(short, long)]
+    output: Option<String>,
 
-    println!("running the Gibbs sampler...");
+    /// Iterations between checkpoints [default: 1000]
+    #[arg(long)]
+    checkpoint_every: Option<usize>,
+}
 
-    let mut gibbs_chain = gibbs::Chain::new(data.clone());
+#[derive(Args)]
+struct ConvertArgs {
+    /// A binary trace, or CSV files of draws with the same columns, one per chain
+    #[arg(required = true)]
+    inputs: Vec<String>,
 
-    let gibbs_samples = gibbs_chain.run(1000, 8000, 42);
+    /// Output file: CSV for a trace, a binary trace for CSV files
+    #[arg(short, long)]
+    to: String,
 
-    println!("Gibbs sampler results:");
+    /// Only these chains of the trace
+    #[arg(long, value_delimiter = ',')]
+    chains: Option<Vec<u32>>,
 
-    println!("{}", gibbs::Parameters::summary(&gibbs_samples));
+    /// Only these columns of the trace
+    #[arg(long, value_delimiter = ',')]
+    columns: Option<Vec<String>>,
 
-    println!("saving the samples to file 'gibbs_samples.csv'...");
+    /// First draw of every chain to convert, from 0
+    #[arg(long)]
+    from: Option<u64>,
 
-    gibbs::Parameters::save_to_csv(&gibbs_samples, "gibbs_samples.csv");
+    /// Draw of every chain to stop before
+    #[arg(long)]
+    until: Option<u64>,
+}
+
+#[derive(Args)]
+struct ImportanceArgs {
+    #[command(flatten)]
+    data: DataArgs,
+
+    /// Number of draws from the proposal [default: 10000]
+    #[arg(long)]
+    samples: Option<usize>,
+}
 
-    println!("done! :)");
+fn main() -> Result<()>{
+    color_eyre::install()?;
+
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::All(SamplerArgs::default()));
+
+    // converting needs no configuration
+    if let Command::Convert(args) = &command {
+        if let (Some(from), Some(until)) = (args.from, args.until) {
+            if from > until {
+                bail!("--from {} is after --until {}", from, until);
+            }
+        }
+        let selection = trace::Selection {
+            chains: args.chains.clone(),
+            iterations: (args.from.is_some() || args.until.is_some()).then(|| args.from.unwrap_or(0)..args.until.unwrap_or(u64::MAX)),
+            columns: args.columns.clone(),
+        };
+        return run::convert(&args.inputs, &args.to, &selection);
+    }
+
+    // defaults, then the configuration file, then the command line
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    match &command {
+        Command::Mh(args) | Command::Hmc(args) | Command::Gibbs(args) | Command::All(args) => {
+            apply_data_args(&mut config, &args.data);
+            let chain = &args.chain;
+            config.burnin = chain.burnin.unwrap_or(config.burnin);
+            config.samples = chain.samples.unwrap_or(config.samples);
+            config.thin = chain.thin.unwrap_or(config.thin);
+            config.chains = chain.chains.or(config.chains);
+            config.checkpoint = chain.checkpoint.clone().or(config.checkpoint);
+            config.checkpoint_every = chain.checkpoint_every.unwrap_or(config.checkpoint_every);
+            config.output_format = chain.output_format.unwrap_or(config.output_format);
+            config.flush_every = chain.flush_every.unwrap_or(config.flush_every);
+            config.summary = config.summary && !chain.no_summary;
+            config.metadata = config.metadata || chain.metadata;
+            if config.chains.is_none() {
+                config.chains = Some(if let Command::All(_) = command { 4 } else { 1 });
+            }
+        }
+        Command::Importance(args) => {
+            apply_data_args(&mut config, &args.data);
+            config.importance.samples = args.samples.unwrap_or(config.importance.samples);
+        }
+        Command::Resume(args) => {
+            config.checkpoint = Some(args.checkpoint.clone());
+            config.data = args.input.clone().unwrap_or(config.data);
+            config.output = args.output.clone().unwrap_or(config.output);
+            config.checkpoint_every = args.checkpoint_every.unwrap_or(config.checkpoint_every);
+        }
+        Command::Convert(_) => unreachable!(),
+    }
+    config.validate()?;
+
+    // the resolved configuration goes next to the results, so that the run can be repeated with --config
+    std::fs::create_dir_all(&config.output)?;
+    let config_file = run::output_file(&config.output, match cli.config.as_deref().map(|c| c.to_lowercase()) {
+        Some(c) if c.ends_with(".json") => "config.json",
+        _ => "config.toml",
+    });
+    println!("saving the configuration to file '{}'...", config_file);
+    config.save(&config_file)?;
+
+    match command {
+        Command::Mh(_) => run::sampler(multichain::Sampler::MH, &config),
+        Command::Hmc(_) => run::sampler(multichain::Sampler::HMC, &config),
+        Command::Gibbs(_) => run::sampler(multichain::Sampler::Gibbs, &config),
+        Command::Importance(_) => run::importance(&config),
+        Command::All(_) => run::all(&config),
+        Command::Resume(_) => run::resume(&config),
+        Command::Convert(_) => unreachable!(),
+    }
+}
 
-    Ok(())
+fn apply_data_args(config: &mut Config, args: &DataArgs) {
+    if let Some(input) = &args.input {
+        config.data = input.clone();
+    }
+    if let Some(output) = &args.output {
+        config.output = output.clone();
+    }
+    config.seed = args.seed.unwrap_or(config.seed);
 }
@@ -0,0 +1,195 @@
+//! Maximum a posteriori estimation with L-BFGS.
+//!
+//! The mode of [`crate::mh::log_posterior`] is searched in the unconstrained space
+//! `u = (log s, logit tau, mu, gamma)`. The mode can start any of the chains, e.g. with
+//! [`crate::hmc::Chain::with_start`]; the objective, the optimiser and the hessian are shared
+//! with [`crate::laplace`], [`crate::advi`] and [`crate::em`].
+
+use crate::data::Data;
+use crate::{hmc, mh};
+use crate::linalg::{self, Matrix};
+
+// number of correction pairs kept by L-BFGS
+static HISTORY: usize = 10;
+static MAX_ITER: usize = 1000;
+static GRAD_TOLERANCE: f64 = 1e-6;
+// sufficient decrease constant and step shrinkage of the backtracking line search
+static ARMIJO: f64 = 1e-4;
+static BACKTRACK: f64 = 0.5;
+// step of the central differences used for the hessian
+static FD_STEP: f64 = 1e-5;
+
+/// One iteration of [`lbfgs`]: the objective, the norm of its gradient and the length of the step taken.
+pub struct TraceEntry {
+    pub iter: usize,
+    pub value: f64,
+    pub grad_norm: f64,
+    pub step: f64,
+}
+
+/// The posterior mode and the curvature there.
+pub struct Output {
+    /// The mode as `(s, tau, mu, gamma)`, ready for `Chain::with_start`.
+    pub mode: Vec<f64>,
+    pub mode_unconstrained: Vec<f64>,
+    /// [`crate::hmc::U`] at the mode.
+    pub value: f64,
+    /// Hessian of the negative log posterior in the unconstrained space.
+    pub hessian: Matrix,
+    pub trace: Vec<TraceEntry>,
+    pub converged: bool,
+}
+
+/// Posterior mode, the minimum of [`hmc::U`] = -[`mh::log_posterior`], searched over
+/// `u = (log s, logit tau, mu, gamma)` so that no constraints are needed.
+pub fn run(data: &Data, start: &[f64]) -> Output {
+    let objective = |u: &[f64]| neg_log_posterior(data, u, false);
+
+    let (u, trace, converged) = lbfgs(objective, mh::Parameters::from_slice(start).to_unconstrained());
+
+    let hessian = hessian(|x| objective(x).1, &u);
+    let (value, _) = objective(&u);
+
+    Output {
+        mode: mh::Parameters::from_unconstrained(&u).0.to_vec(),
+        mode_unconstrained: u,
+        value,
+        hessian,
+        trace,
+        converged,
+    }
+}
+
+/// [`hmc::U`] and its gradient in the unconstrained space; with `jacobian` the
+/// log jacobian of the transform is subtracted, which gives the negative log density of `u` itself.
+pub fn neg_log_posterior(data: &Data, u: &[f64], jacobian: bool) -> (f64, Vec<f64>) {
+    let q = mh::Parameters::from_unconstrained(u).0.to_vec();
+    let mut value = hmc::U(data, &q);
+    let mut grad = hmc::dU(data, &q);
+
+    // chain rule: ds/du0 = s, dtau/du1 = tau (1 - tau)
+    grad[0] *= q[0];
+    grad[1] *= q[1] * (1.0 - q[1]);
+
+    if jacobian {
+        value -= u[0] + q[1].ln() + (1.0 - q[1]).ln();
+        grad[0] -= 1.0;
+        grad[1] -= 1.0 - 2.0 * q[1];
+    }
+
+    (value, grad)
+}
+
+/// Limited memory BFGS with a backtracking (Armijo) line search;
+/// returns the minimiser, the trace of the iterations and whether the gradient tolerance was reached.
+pub fn lbfgs<F: Fn(&[f64]) -> (f64, Vec<f64>)>(f: F, x0: Vec<f64>) -> (Vec<f64>, Vec<TraceEntry>, bool) {
+    let mut x = x0;
+    let (mut fx, mut g) = f(&x);
+
+    let mut s_hist: Vec<Vec<f64>> = Vec::new();
+    let mut y_hist: Vec<Vec<f64>> = Vec::new();
+
+    let mut trace = vec![TraceEntry { iter: 0, value: fx, grad_norm: norm(&g), step: 0.0 }];
+
+    for iter in 1..=MAX_ITER {
+        if norm(&g) < GRAD_TOLERANCE {
+            return (x, trace, true);
+        }
+
+        let mut d: Vec<f64> = two_loop(&g, &s_hist, &y_hist).iter().map(|v| -v).collect();
+
+        // fall back to steepest descent if the direction is not a descent direction
+        if linalg::dot(&d, &g) >= 0.0 {
+            d = g.iter().map(|v| -v).collect();
+            s_hist.clear();
+            y_hist.clear();
+        }
+
+        let slope = linalg::dot(&d, &g);
+        let mut step = 1.0;
+        let (x_new, fx_new, g_new) = loop {
+            let x_try: Vec<f64> = x.iter().zip(d.iter()).map(|(a, b)| a + step * b).collect();
+            let (f_try, g_try) = f(&x_try);
+            if f_try.is_finite() && f_try <= fx + ARMIJO * step * slope {
+                break (x_try, f_try, g_try);
+            }
+            step *= BACKTRACK;
+            if step < 1e-20 {
+                return (x, trace, false);
+            }
+        };
+
+        let s: Vec<f64> = x_new.iter().zip(x.iter()).map(|(a, b)| a - b).collect();
+        let y: Vec<f64> = g_new.iter().zip(g.iter()).map(|(a, b)| a - b).collect();
+
+        // only keep pairs that preserve positive definiteness
+        if linalg::dot(&s, &y) > 1e-12 {
+            s_hist.push(s);
+            y_hist.push(y);
+            if s_hist.len() > HISTORY {
+                s_hist.remove(0);
+                y_hist.remove(0);
+            }
+        }
+
+        x = x_new;
+        fx = fx_new;
+        g = g_new;
+
+        trace.push(TraceEntry { iter, value: fx, grad_norm: norm(&g), step });
+    }
+
+    let converged = norm(&g) < GRAD_TOLERANCE;
+    (x, trace, converged)
+}
+
+// approximate inverse hessian times g from the stored correction pairs
+fn two_loop(g: &[f64], s_hist: &[Vec<f64>], y_hist: &[Vec<f64>]) -> Vec<f64> {
+    let mut q = g.to_vec();
+    let k = s_hist.len();
+    let mut alpha = vec![0.0; k];
+
+    for i in (0..k).rev() {
+        let rho = 1.0 / linalg::dot(&y_hist[i], &s_hist[i]);
+        alpha[i] = rho * linalg::dot(&s_hist[i], &q);
+        for (qj, yj) in q.iter_mut().zip(y_hist[i].iter()) {
+            *qj -= alpha[i] * yj;
+        }
+    }
+
+    // initial hessian scaled as in Nocedal & Wright (7.20)
+    if k > 0 {
+        let gamma = linalg::dot(&s_hist[k - 1], &y_hist[k - 1]) / linalg::dot(&y_hist[k - 1], &y_hist[k - 1]);
+        q.iter_mut().for_each(|v| *v *= gamma);
+    }
+
+    for i in 0..k {
+        let rho = 1.0 / linalg::dot(&y_hist[i], &s_hist[i]);
+        let beta = rho * linalg::dot(&y_hist[i], &q);
+        for (qj, sj) in q.iter_mut().zip(s_hist[i].iter()) {
+            *qj += (alpha[i] - beta) * sj;
+        }
+    }
+
+    q
+}
+
+/// Hessian from symmetrised central differences of the gradient `grad` at `x`.
+pub fn hessian<G: Fn(&[f64]) -> Vec<f64>>(grad: G, x: &[f64]) -> Matrix {
+    let n = x.len();
+
+    // column j holds the derivative of the gradient with respect to x_j
+    let cols: Vec<Vec<f64>> = (0..n).map(|j| {
+        let mut xp = x.to_vec();
+        let mut xm = x.to_vec();
+        xp[j] += FD_STEP;
+        xm[j] -= FD_STEP;
+        grad(&xp).iter().zip(grad(&xm).iter()).map(|(a, b)| (a - b) / (2.0 * FD_STEP)).collect()
+    }).collect();
+
+    (0..n).map(|i| (0..n).map(|j| 0.5 * (cols[j][i] + cols[i][j])).collect()).collect()
+}
+
+fn norm(x: &[f64]) -> f64 {
+    linalg::dot(x, x).sqrt()
+}
@@ -0,0 +1,80 @@
+//! Draws with their metadata.
+//!
+//! Every row starts with the chain id, the iteration after the burn-in (counted from 1, so
+//! thinned rows skip iterations), the log density the sampler targets
+//! [`Resumable::log_density`], i.e. [`mh::log_posterior`] for all three samplers, the Gaussian
+//! log-likelihood [`em::log_likelihood`] and the statistics of the last step of the sampler,
+//! followed by the parameters:
+//!
+//! - MH: whether the `s`, `tau`, `mu` and `gamma` updates were accepted (1 or 0),
+//! - HMC: the acceptance probability, the Hamiltonian and whether the trajectory diverged,
+//! - Gibbs: nothing, every update is accepted.
+
+use crate::checkpoint::Resumable;
+use crate::data::Data;
+use crate::diagnostics::Draw;
+use crate::multichain::{Sampler, Settings};
+use crate::sink::{self, Sink};
+use crate::{data, em, gibbs, hmc, mh};
+use color_eyre::Result;
+
+/// Columns written before the statistics of the sampler.
+pub static COLUMNS: [&str; 4] = ["chain", "iteration", "lp", "log_lik"];
+
+/// A chain that reports statistics of its last step.
+pub trait Stats: Resumable {
+    /// Names of the statistics.
+    const STATS: &'static [&'static str];
+
+    /// Values of [`Stats::STATS`] for the last step.
+    fn stats(&self) -> Vec<f64>;
+}
+
+/// All columns of the rows written for `sampler` with `n_traits` traits.
+pub fn columns(sampler: Sampler, n_traits: usize) -> Vec<String> {
+    let stats = match sampler {
+        Sampler::MH => <mh::Chain as Stats>::STATS,
+        Sampler::HMC => <hmc::Chain as Stats>::STATS,
+        Sampler::Gibbs => <gibbs::Chain as Stats>::STATS,
+    };
+    let mut columns: Vec<String> = COLUMNS.iter().chain(stats).map(|c| c.to_string()).collect();
+    columns.extend(data::parameter_names(n_traits));
+    columns
+}
+
+/// The row of the current draw of `chain`.
+pub fn row<C>(chain: &C, chain_id: usize, iteration: usize) -> Vec<f64>
+where
+    C: Stats,
+    C::Draw: Draw,
+{
+    let values = chain.draw().values();
+    let mut row = vec![
+        chain_id as f64,
+        iteration as f64,
+        chain.log_density(),
+        em::log_likelihood(chain.data(), &values),
+    ];
+    row.extend(chain.stats());
+    row.extend(values);
+    row
+}
+
+/// Like [`sink::stream`], writing the rows of [`row`] instead of the draws.
+pub fn stream<C>(chain: &mut C, n_burnin: usize, n_samples: usize, thin: usize, seed: u64, chain_id: usize, sink: &mut dyn Sink) -> Result<()>
+where
+    C: Stats,
+    C::Draw: Draw,
+{
+    sink::stream_rows(chain, n_burnin, n_samples, thin, seed, sink, |c, iteration| row(c, chain_id, iteration))
+}
+
+/// The same draws as [`crate::multichain::run_chain`], written to `sink` in the columns of [`columns`].
+pub fn stream_chain(data: Data, sampler: Sampler, start: &[f64], settings: &Settings, seed: u64, chain_id: usize, sink: &mut dyn Sink) -> Result<()> {
+    let (n_burnin, n_samples, thin) = (settings.n_burnin, settings.n_samples, settings.thin);
+    match sampler {
+        Sampler::MH => stream(&mut mh::Chain::with_tuning(data, start, settings.mh), n_burnin, n_samples, thin, seed, chain_id, sink),
+        Sampler::HMC => stream(&mut hmc::Chain::with_tuning(data, start, settings.hmc.clone()), n_burnin, n_samples, thin, seed, chain_id, sink),
+        Sampler::Gibbs => stream(&mut gibbs::Chain::with_start(data, start), n_burnin, n_samples, thin, seed, chain_id, sink),
+    }
+}
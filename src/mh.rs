@@ -1,42 +1,71 @@
-use crate::data::{Data, Row};
+//! Random-walk Metropolis-Hastings.
+
+use crate::data::{self, Data, Row};
+use crate::{checkpoint, diagnostics, metadata, sink, stan};
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
 use rand::distributions::Distribution;
 use statrs::distribution::{Uniform, Normal};
 use serde::{Serialize, Deserialize};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
 
 static SPROPSD: f64 = 0.2;
 static MEANPROPSD: f64 = 0.5;
 
+/// A draw of `(s, tau, mu, gamma)` with one `mu` and `gamma` per trait; the other samplers convert their draws to this type.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Parameters {
     s: f64,
     tau: f64,
-    mu1: f64,
-    mu2: f64,
-    gamma1: f64,
-    gamma2: f64,
+    mu: Vec<f64>,
+    gamma: Vec<f64>,
+}
+
+/// Standard deviations of the random walk proposals.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Tuning {
+    pub s_proposal_sd: f64,
+    pub mean_proposal_sd: f64,
 }
 
+impl Default for Tuning {
+    fn default() -> Self {
+        Self { s_proposal_sd: SPROPSD, mean_proposal_sd: MEANPROPSD }
+    }
+}
+
+/// A single Metropolis-within-Gibbs chain, updating `s`, `tau`, `mu` and `gamma` in turn.
 pub struct Chain {
     data: Data,
     parameters: Parameters,
+    tuning: Tuning,
+    // acceptance probabilities of the s, tau, mu and gamma updates of the last step, and whether they were accepted
+    accept_prob: [f64; 4],
+    accepted: [bool; 4],
 }
 
 impl Chain {
+    /// A chain with the default tuning, started at [`default_start`].
     pub fn new(data: Data) -> Self {
-        let parameters = Parameters {
-            s: 1.0,
-            tau: 0.5,
-            mu1: 0.0,
-            mu2: 0.0,
-            gamma1: 0.0,
-            gamma2: 0.0,
-        };
-        Self { data, parameters }
+        let start = default_start(data::n_traits(&data));
+        Self::with_start(data, &start)
+    }
+
+    /// `start` is `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`, e.g. the mode found by [`crate::map::run`].
+    pub fn with_start(data: Data, start: &[f64]) -> Self {
+        Self::with_tuning(data, start, Tuning::default())
     }
 
+    /// Like [`Chain::with_start`], with the given proposal standard deviations.
+    pub fn with_tuning(data: Data, start: &[f64], tuning: Tuning) -> Self {
+        let parameters = Parameters::from_slice(start);
+        Self { data, parameters, tuning, accept_prob: [0.0; 4], accepted: [false; 4] }
+    }
+
+    /// Runs `n_burnin` iterations, then returns the next `n_samples` states.
     pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
-        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
         for _ in 0..n_burnin {
             self.step(&mut rng);
         }
@@ -51,198 +80,303 @@ impl Chain {
         samples
     }
 
-    fn step(&mut self, rng: &mut StdRng) {
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
         self.update_s(rng);
         self.update_tau(rng);
         self.update_mu(rng);
         self.update_gamma(rng);
     }
 
-    fn l_ratio(&self, new_parameters: &Parameters) -> f64 {
-        let old_l: f64 = self.data.iter().map(|row| row_likelihood(&row, &self.parameters)).product();
-        let new_l: f64 = self.data.iter().map(|row| row_likelihood(&row, new_parameters)).product();
-        new_l / old_l
+    // posterior ratio of `new_parameters` to the current ones times the proposal correction,
+    // summed in log space so that it does not underflow with many traits
+    fn l_ratio(&self, new_parameters: &Parameters, log_c: f64) -> f64 {
+        let old_l: f64 = self.data.iter().map(|row| log_row_likelihood(row, &self.parameters)).sum::<f64>() - self.parameters.s.ln();
+        let new_l: f64 = self.data.iter().map(|row| log_row_likelihood(row, new_parameters)).sum::<f64>() - new_parameters.s.ln();
+        (new_l - old_l + log_c).exp()
     }
 
-    fn update_s(&mut self, rng: &mut StdRng) {
-        let normal = Normal::new(self.parameters.s, SPROPSD).unwrap();
+    fn update_s(&mut self, rng: &mut ChaCha12Rng) {
+        let normal = Normal::new(self.parameters.s, self.tuning.s_proposal_sd).unwrap();
         let new_s = normal.sample(rng);
 
+        self.accept_prob[0] = 0.0;
+        self.accepted[0] = false;
+
         if new_s > 0.0 && new_s <= 10.0 {
 
-            // calculate correction factor 
-            let c = pnorm(self.parameters.s, new_s, SPROPSD) / pnorm(new_s, self.parameters.s, SPROPSD);
+            // calculate the log of the correction factor
+            let log_c = lnorm(self.parameters.s, new_s, self.tuning.s_proposal_sd) - lnorm(new_s, self.parameters.s, self.tuning.s_proposal_sd);
 
             let new_parameters = Parameters {
                 s: new_s,
-                ..self.parameters
+                ..self.parameters.clone()
             };
 
-            let l_ratio = self.l_ratio(&new_parameters) * c;
+            let l_ratio = self.l_ratio(&new_parameters, log_c);
+
+            self.accept_prob[0] = stan::accept_stat(l_ratio);
 
             if l_ratio >= 1.0 || l_ratio > rng.gen() {
                 self.parameters = new_parameters;
+                self.accepted[0] = true;
             }
         }
     }
 
-    fn update_tau(&mut self, rng: &mut StdRng) {
+    fn update_tau(&mut self, rng: &mut ChaCha12Rng) {
         let n = Uniform::new(0.0, 1.0).unwrap();
         let new_tau = n.sample(rng);
         // proposal distribution is symmetric => correction factor is 1
 
         let new_parameters = Parameters {
             tau: new_tau,
-            ..self.parameters
+            ..self.parameters.clone()
         };
 
-        let l_ratio = self.l_ratio(&new_parameters);
+        let l_ratio = self.l_ratio(&new_parameters, 0.0);
+
+        self.accept_prob[1] = stan::accept_stat(l_ratio);
+        self.accepted[1] = false;
 
         if l_ratio >= 1.0 || l_ratio > rng.gen() {
             self.parameters = new_parameters;
+            self.accepted[1] = true;
         }
 
     }
 
-    fn update_mu(&mut self, rng: &mut StdRng) {
-        let n1 = Normal::new(self.parameters.mu1, MEANPROPSD).unwrap();
-        let n2 = Normal::new(self.parameters.mu2, MEANPROPSD).unwrap();
-
-        let new_mu1 = n1.sample(rng);
-        let new_mu2 = n2.sample(rng);
-
-        let c = pnorm(self.parameters.mu1, new_mu1, MEANPROPSD) * pnorm(self.parameters.mu2, new_mu2, MEANPROPSD) / pnorm(new_mu1, self.parameters.mu1, MEANPROPSD) / pnorm(new_mu2, self.parameters.mu2, MEANPROPSD);
+    fn update_mu(&mut self, rng: &mut ChaCha12Rng) {
+        let new_mu = self.propose_means(rng, &self.parameters.mu);
+        let log_c = self.log_correction(&self.parameters.mu, &new_mu);
 
         let new_parameters = Parameters {
-            mu1: new_mu1,
-            mu2: new_mu2,
-            ..self.parameters
+            mu: new_mu,
+            ..self.parameters.clone()
         };
 
-        let l_ratio = self.l_ratio(&new_parameters) * c;
+        let l_ratio = self.l_ratio(&new_parameters, log_c);
+
+        self.accept_prob[2] = stan::accept_stat(l_ratio);
+        self.accepted[2] = false;
 
         if l_ratio >= 1.0 || l_ratio > rng.gen() {
             self.parameters = new_parameters;
+            self.accepted[2] = true;
         }
     }
 
-    fn update_gamma(&mut self, rng: &mut StdRng) {
-        let n1 = Normal::new(self.parameters.gamma1, MEANPROPSD).unwrap();
-        let n2 = Normal::new(self.parameters.gamma2, MEANPROPSD).unwrap();
-
-        let new_gamma1 = n1.sample(rng);
-        let new_gamma2 = n2.sample(rng);
-
-        let c = pnorm(self.parameters.gamma1, new_gamma1, MEANPROPSD) * pnorm(self.parameters.gamma2, new_gamma2, MEANPROPSD) / pnorm(new_gamma1, self.parameters.gamma1, MEANPROPSD) / pnorm(new_gamma2, self.parameters.gamma2, MEANPROPSD);
+    fn update_gamma(&mut self, rng: &mut ChaCha12Rng) {
+        let new_gamma = self.propose_means(rng, &self.parameters.gamma);
+        let log_c = self.log_correction(&self.parameters.gamma, &new_gamma);
 
         let new_parameters = Parameters {
-            gamma1: new_gamma1,
-            gamma2: new_gamma2,
-            ..self.parameters
+            gamma: new_gamma,
+            ..self.parameters.clone()
         };
 
-        let l_ratio = self.l_ratio(&new_parameters) * c;
+        let l_ratio = self.l_ratio(&new_parameters, log_c);
+
+        self.accept_prob[3] = stan::accept_stat(l_ratio);
+        self.accepted[3] = false;
 
         if l_ratio >= 1.0 || l_ratio > rng.gen() {
             self.parameters = new_parameters;
+            self.accepted[3] = true;
         }
     }
+
+    // a normal random walk step of every trait, drawn in trait order
+    fn propose_means(&self, rng: &mut ChaCha12Rng, means: &[f64]) -> Vec<f64> {
+        means.iter().map(|&m| Normal::new(m, self.tuning.mean_proposal_sd).unwrap().sample(rng)).collect()
+    }
+
+    // log of the correction factor of a joint proposal from `old` to `new`
+    fn log_correction(&self, old: &[f64], new: &[f64]) -> f64 {
+        let sd = self.tuning.mean_proposal_sd;
+        old.iter().zip(new.iter()).map(|(o, n)| lnorm(*o, *n, sd) - lnorm(*n, *o, sd)).sum()
+    }
 }
 
-// essentially the numerator of the Bayes' formula: normal likelihood * prior on sigma^2 
-// other priors are uniform, so they don't play a role here
-fn row_likelihood(r: &Row, p: &Parameters) -> f64 {
-    match r.group {
-        1 => { pnorm(r.x1, p.mu1, p.s) * pnorm(r.x2, p.mu2, p.s)/p.s },
-        2 => { pnorm(r.x1, p.gamma1, p.s) * pnorm(r.x2, p.gamma2, p.s)/p.s   },
-        3 => { pnorm(r.x1, 0.5*p.mu1 + 0.5*p.gamma1, p.s) * pnorm(r.x2, 0.5*p.mu2 + 0.5*p.gamma2, p.s)/p.s  },
-        4 => { pnorm(r.x1, p.tau*p.mu1 + (1. - p.tau)*p.gamma1, p.s) * pnorm(r.x2, p.tau*p.mu2 + (1. - p.tau)*p.gamma2, p.s)/p.s  },
+impl checkpoint::Resumable for Chain {
+    type State = (Parameters, Tuning);
+    type Draw = Parameters;
+
+    const NAME: &'static str = "mh";
+
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn step(&mut self, rng: &mut ChaCha12Rng) {
+        Chain::step(self, rng)
+    }
+
+    fn state(&self) -> Self::State {
+        (self.parameters.clone(), self.tuning)
+    }
+
+    fn restore(&mut self, state: &Self::State) {
+        (self.parameters, self.tuning) = state.clone();
+    }
+
+    fn draw(&self) -> Parameters {
+        self.parameters.clone()
+    }
+}
+
+impl metadata::Stats for Chain {
+    const STATS: &'static [&'static str] = &["accepted_s", "accepted_tau", "accepted_mu", "accepted_gamma"];
+
+    fn stats(&self) -> Vec<f64> {
+        self.accepted.iter().map(|&a| a as u8 as f64).collect()
+    }
+}
+
+impl stan::Diagnostics for Chain {
+    const COLUMNS: &'static [&'static str] = &["accept_stat__"];
+
+    // the mean acceptance probability of the four updates
+    fn diagnostics(&self) -> Vec<f64> {
+        vec![self.accept_prob.iter().sum::<f64>() / 4.0]
+    }
+}
+
+/// The normal likelihood of a row, the product of the densities of its traits.
+pub fn row_likelihood(r: &Row, p: &Parameters) -> f64 {
+    log_row_likelihood(r, p).exp()
+}
+
+/// Log of [`row_likelihood`], a sum over the traits that stays finite however many there are.
+pub fn log_row_likelihood(r: &Row, p: &Parameters) -> f64 {
+    let mean = |k: usize| match r.group {
+        1 => p.mu[k],
+        2 => p.gamma[k],
+        3 => 0.5*p.mu[k] + 0.5*p.gamma[k],
+        4 => p.tau*p.mu[k] + (1. - p.tau)*p.gamma[k],
         _ => unreachable!(),
+    };
+    r.x.iter().enumerate().map(|(k, x)| lnorm(*x, mean(k), p.s)).sum::<f64>()
+}
+
+/// The starting point of [`Chain::new`], `(1, 0.5, 0, ..., 0)`.
+pub fn default_start(n_traits: usize) -> Vec<f64> {
+    let mut start = vec![0.0; 2 + 2 * n_traits];
+    start[0] = 1.0;
+    start[1] = 0.5;
+    start
+}
+
+/// Log of the unnormalised posterior, -inf outside of the prior support
+/// (same support as the proposals in [`Chain`]: 0 < s <= 10, 0 < tau < 1).
+///
+/// This is the posterior every sampler and estimator of the crate targets: the normal
+/// likelihood of the rows, with its `2 pi` constants, times the prior `1/s` on the variance
+/// and flat priors on `tau`, `mu` and `gamma`. Inside the support it equals
+/// `-`[`crate::hmc::U`]. The priors on `s`, `mu` and `gamma` are improper, so their
+/// normalising constants are dropped, and the evidence estimated by `smc`, `ais`, `nested`,
+/// `bridge` and `laplace` is the normalising constant of this density rather than a
+/// marginal likelihood that can be compared with other models.
+pub fn log_posterior(data: &Data, p: &Parameters) -> f64 {
+    if p.s <= 0.0 || p.s > 10.0 || p.tau <= 0.0 || p.tau >= 1.0 {
+        return f64::NEG_INFINITY;
     }
+    data.iter().map(|row| log_row_likelihood(row, p)).sum::<f64>() - p.s.ln()
 }
 
-// normal likelihood  
-fn pnorm(x:f64, mu: f64, s: f64) -> f64 {
-    (-(x - mu).powi(2)/2.0/s).exp()/s.sqrt()
+// log of the normal density with variance s
+fn lnorm(x: f64, mu: f64, s: f64) -> f64 {
+    -(x - mu).powi(2)/2.0/s - 0.5*(std::f64::consts::TAU*s).ln()
 }
 
 impl Parameters {
-    pub fn save_to_csv(ps: &Vec<Parameters>, filename: &str) {
-        let mut wtr = csv::Writer::from_path(filename).unwrap();
-
-        for p in ps {
-            wtr.serialize(p).unwrap();
+    /// Parameters in the order `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`,
+    /// which is the same as the column order of the csv output.
+    pub fn from_slice(q: &[f64]) -> Parameters {
+        let d = data::n_traits_of(q.len());
+        Parameters {
+            s: q[0],
+            tau: q[1],
+            mu: q[2..2 + d].to_vec(),
+            gamma: q[2 + d..2 + 2 * d].to_vec(),
         }
     }
 
-    pub fn summary(ps: &Vec<Parameters>) -> String {
-        // compute mean of each parameter
-        let n = ps.len() as f64;
-
-        let mut s = 0.0;
-        let mut tau = 0.0;
-        let mut mu1 = 0.0;
-        let mut mu2 = 0.0;
-        let mut gamma1 = 0.0;
-        let mut gamma2 = 0.0;
-
-        for p in ps {
-            s += p.s;
-            tau += p.tau;
-            mu1 += p.mu1;
-            mu2 += p.mu2;
-            gamma1 += p.gamma1;
-            gamma2 += p.gamma2;
-        }
-        
-        s /= n;
-        tau /= n;
-        mu1 /= n;
-        mu2 /= n;
-        gamma1 /= n;
-        gamma2 /= n;
-
-        // compute 5th and 95th quantiles of each parameter
-        let mut s_vec = Vec::new();
-        let mut tau_vec = Vec::new();
-        let mut mu1_vec = Vec::new();
-        let mut mu2_vec = Vec::new();
-        let mut gamma1_vec = Vec::new();
-        let mut gamma2_vec = Vec::new();
-
-        for p in ps {
-            s_vec.push(p.s);
-            tau_vec.push(p.tau);
-            mu1_vec.push(p.mu1);
-            mu2_vec.push(p.mu2);
-            gamma1_vec.push(p.gamma1);
-            gamma2_vec.push(p.gamma2);
-        }
+    /// `(s, tau, mu1, ..., muD, gamma1, ..., gammaD)`
+    pub fn to_vec(&self) -> Vec<f64> {
+        let mut v = vec![self.s, self.tau];
+        v.extend_from_slice(&self.mu);
+        v.extend_from_slice(&self.gamma);
+        v
+    }
+
+    /// Number of traits.
+    pub fn n_traits(&self) -> usize {
+        self.mu.len()
+    }
+
+    /// Unconstrained coordinates `(log s, logit tau, mu, gamma)`.
+    pub fn to_unconstrained(&self) -> Vec<f64> {
+        let mut u = self.to_vec();
+        u[0] = self.s.ln();
+        u[1] = (self.tau / (1.0 - self.tau)).ln();
+        u
+    }
 
-        s_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        tau_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        mu2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma1_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        gamma2_vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    /// Inverse of [`Parameters::to_unconstrained`], together with the log of the jacobian
+    /// |d(s, tau)/du|, which turns a density in s and tau into a density in u.
+    pub fn from_unconstrained(u: &[f64]) -> (Parameters, f64) {
+        let s = u[0].exp();
+        let tau = 1.0 / (1.0 + (-u[1]).exp());
+        let log_jacobian = u[0] + tau.ln() + (1.0 - tau).ln();
 
-        let s_5 = s_vec[(n*0.05) as usize];
-        let tau_5 = tau_vec[(n*0.05) as usize];
-        let mu1_5 = mu1_vec[(n*0.05) as usize];
-        let mu2_5 = mu2_vec[(n*0.05) as usize];
-        let gamma1_5 = gamma1_vec[(n*0.05) as usize];
-        let gamma2_5 = gamma2_vec[(n*0.05) as usize];
+        (Parameters { s, tau, ..Parameters::from_slice(u) }, log_jacobian)
+    }
 
-        let s_95 = s_vec[(n*0.95) as usize];
-        let tau_95 = tau_vec[(n*0.95) as usize];
-        let mu1_95 = mu1_vec[(n*0.95) as usize];
-        let mu2_95 = mu2_vec[(n*0.95) as usize];
-        let gamma1_95 = gamma1_vec[(n*0.95) as usize];
-        let gamma2_95 = gamma2_vec[(n*0.95) as usize];
+    /// Reads draws written by [`Parameters::save_to_csv`] (or by the other samplers, which use the same columns).
+    pub fn load_from_csv(filename: &str) -> Result<Vec<Parameters>> {
+        let mut rdr = csv::Reader::from_path(filename)?;
+        let headers: Vec<String> = rdr.headers()?.iter().map(|h| h.to_string()).collect();
+        if headers != data::parameter_names(data::n_traits_of(headers.len())) {
+            bail!("expected the columns s, tau, mu and gamma in '{}', got {:?}", filename, headers);
+        }
+        let mut ps = Vec::new();
+        for result in rdr.deserialize() {
+            let q: Vec<f64> = result?;
+            ps.push(Parameters::from_slice(&q));
+        }
+        Ok(ps)
+    }
 
-        // format mean and quantiles into a summary to print out
+    /// Writes one row per draw, with the columns of [`data::parameter_names`].
+    pub fn save_to_csv(ps: &[Parameters], filename: &str) -> Result<()> {
+        sink::save(ps, filename, sink::Format::Csv)
+    }
 
-        format!("s: {:.3} [{:.3}, {:.3}]\ntau: {:.3} [{:.3}, {:.3}]\nmu1: {:.3} [{:.3}, {:.3}]\nmu2: {:.3} [{:.3}, {:.3}]\ngamma1: {:.3} [{:.3}, {:.3}]\ngamma2: {:.3} [{:.3}, {:.3}]", s, s_5, s_95, tau, tau_5, tau_95, mu1, mu1_5, mu1_95, mu2, mu2_5, mu2_95, gamma1, gamma1_5, gamma1_95, gamma2, gamma2_5, gamma2_95)
+    /// Mean and 5-95% interval of every parameter, with the ESS and MCSE columns of [`crate::diagnostics`].
+    pub fn summary(ps: &[Parameters]) -> String {
+        diagnostics::summary(ps)
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // with 24 traits the likelihood of the data is far below the smallest f64, so the ratios
+    // of the updates are only defined in log space
+    #[test]
+    fn the_means_move_with_many_traits() {
+        let d = 24;
+        let data: Data = (0..200).map(|i| Row {
+            group: (i % 4 + 1) as u8,
+            x: (0..d).map(|k| (i as f64 * 0.37 + k as f64).sin() * 0.3 + if i % 4 == 1 { 1.0 } else { -1.0 }).collect(),
+        }).collect();
+        let start = Parameters::from_slice(&default_start(d));
+        assert_eq!(data.iter().map(|r| row_likelihood(r, &start)).product::<f64>(), 0.0);
+
+        let samples = Chain::new(data).run(100, 200, 42);
+        let moves = samples.windows(2).filter(|w| w[0].mu != w[1].mu).count();
+        assert!(moves > 0);
+        assert!(samples.last().unwrap().mu[0] != 0.0);
     }
-}
\ No newline at end of file
+}
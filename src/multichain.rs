@@ -0,0 +1,232 @@
+//! Several independent chains of one sampler from dispersed starting points, and their convergence.
+//!
+//! Every chain starts from its own draw of the importance proposal and has its own seed; the
+//! split and rank-normalised R-hats compare the chains to flag those that have not mixed.
+
+use crate::data::{self, Data};
+use crate::{diagnostics, gibbs, hmc, importance, mh, sink};
+use color_eyre::Result;
+use rand::prelude::*;
+
+// chains are flagged as not converged above this R-hat
+static RHAT_THRESHOLD: f64 = 1.01;
+
+/// The samplers that can run several chains.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sampler {
+    MH,
+    HMC,
+    Gibbs,
+}
+
+/// The draws of every chain, with where they started and the R-hat of every parameter.
+pub struct Output {
+    pub chains: Vec<Vec<mh::Parameters>>,
+    pub starts: Vec<Vec<f64>>,
+    pub seeds: Vec<u64>,
+    /// See [`split_rhat`] and [`rank_rhat`].
+    pub split_rhat: Vec<f64>,
+    pub rank_rhat: Vec<f64>,
+}
+
+/// Settings shared by every chain: iterations, thinning and the tuning of the samplers.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub n_burnin: usize,
+    pub n_samples: usize,
+    pub thin: usize,
+    pub mh: mh::Tuning,
+    pub hmc: hmc::Tuning,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { n_burnin: 1000, n_samples: 8000, thin: 1, mh: mh::Tuning::default(), hmc: hmc::Tuning::default() }
+    }
+}
+
+/// Runs `n_chains` chains of the given sampler in parallel, each from its own dispersed
+/// starting point (a draw from the importance proposal) and with its own seed.
+pub fn run(data: &Data, sampler: Sampler, n_chains: usize, settings: &Settings, seed: u64) -> Output {
+    let (starts, seeds) = starting_points(data, n_chains, seed);
+
+    let chains: Vec<Vec<mh::Parameters>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = starts.iter().zip(seeds.iter())
+            .map(|(start, &chain_seed)| {
+                let data = data.clone();
+                scope.spawn(move || run_chain(data, sampler, start, settings, chain_seed))
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    output(chains, starts, seeds)
+}
+
+/// Like [`run`], with the draws of chain `c` (from 0) given by `chain(c, start, seed)`, e.g. to also
+/// write them to a file while the chain runs.
+pub fn run_with<F>(data: &Data, n_chains: usize, seed: u64, chain: F) -> Result<Output>
+where
+    F: Fn(usize, &[f64], u64) -> Result<Vec<mh::Parameters>> + Sync,
+{
+    let (starts, seeds) = starting_points(data, n_chains, seed);
+
+    let chains: Vec<Vec<mh::Parameters>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = starts.iter().zip(seeds.iter()).enumerate()
+            .map(|(c, (start, &chain_seed))| {
+                let chain = &chain;
+                scope.spawn(move || chain(c, start, chain_seed))
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Result<_>>()
+    })?;
+
+    Ok(output(chains, starts, seeds))
+}
+
+fn starting_points(data: &Data, n_chains: usize, seed: u64) -> (Vec<Vec<f64>>, Vec<u64>) {
+    assert!(n_chains >= 2, "need at least two chains for R-hat");
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let starts: Vec<Vec<f64>> = (0..n_chains).map(|_| importance::generate_sample(data, &mut rng).to_vec()).collect();
+    let seeds: Vec<u64> = (0..n_chains).map(|_| rng.gen()).collect();
+    (starts, seeds)
+}
+
+fn output(chains: Vec<Vec<mh::Parameters>>, starts: Vec<Vec<f64>>, seeds: Vec<u64>) -> Output {
+    let traces = traces(&chains);
+
+    Output {
+        split_rhat: traces.iter().map(|t| split_rhat(t)).collect(),
+        rank_rhat: traces.iter().map(|t| rank_rhat(t)).collect(),
+        chains,
+        starts,
+        seeds,
+    }
+}
+
+/// A single thinned chain of any of the samplers, as [`mh::Parameters`].
+pub fn run_chain(data: Data, sampler: Sampler, start: &[f64], settings: &Settings, seed: u64) -> Vec<mh::Parameters> {
+    let (n_burnin, n_samples) = (settings.n_burnin, settings.n_samples);
+    let samples = match sampler {
+        Sampler::MH => mh::Chain::with_tuning(data, start, settings.mh).run(n_burnin, n_samples, seed),
+        Sampler::HMC => hmc::Chain::with_tuning(data, start, settings.hmc.clone()).run(n_burnin, n_samples, seed)
+            .iter().map(|p| mh::Parameters::from_slice(&p.to_vec())).collect(),
+        Sampler::Gibbs => gibbs::Chain::with_start(data, start).run(n_burnin, n_samples, seed)
+            .iter().map(|p| mh::Parameters::from_slice(&p.to_vec())).collect(),
+    };
+    diagnostics::thin(&samples, settings.thin)
+}
+
+/// The same draws as [`run_chain`], written to `sink` one at a time.
+pub fn stream_chain(data: Data, sampler: Sampler, start: &[f64], settings: &Settings, seed: u64, sink: &mut dyn sink::Sink) -> Result<()> {
+    let (n_burnin, n_samples, thin) = (settings.n_burnin, settings.n_samples, settings.thin);
+    match sampler {
+        Sampler::MH => sink::stream(&mut mh::Chain::with_tuning(data, start, settings.mh), n_burnin, n_samples, thin, seed, sink),
+        Sampler::HMC => sink::stream(&mut hmc::Chain::with_tuning(data, start, settings.hmc.clone()), n_burnin, n_samples, thin, seed, sink),
+        Sampler::Gibbs => sink::stream(&mut gibbs::Chain::with_start(data, start), n_burnin, n_samples, thin, seed, sink),
+    }
+}
+
+// traces[d][c] is the trace of parameter d in chain c
+fn traces(chains: &[Vec<mh::Parameters>]) -> Vec<Vec<Vec<f64>>> {
+    let vecs: Vec<Vec<Vec<f64>>> = chains.iter().map(|c| c.iter().map(|p| p.to_vec()).collect()).collect();
+    let n = vecs.iter().flatten().next().map_or(0, |v| v.len());
+    (0..n)
+        .map(|d| vecs.iter().map(|c| c.iter().map(|v| v[d]).collect()).collect())
+        .collect()
+}
+
+/// Potential scale reduction of Gelman et al. after splitting every chain in half.
+pub fn split_rhat(chains: &[Vec<f64>]) -> f64 {
+    let n = chains.iter().map(|c| c.len()).min().unwrap() / 2;
+    let halves: Vec<&[f64]> = chains.iter()
+        .flat_map(|c| [&c[..n], &c[n..2 * n]])
+        .collect();
+
+    let m = halves.len() as f64;
+    let nf = n as f64;
+
+    let means: Vec<f64> = halves.iter().map(|h| h.iter().sum::<f64>() / nf).collect();
+    let grand_mean = means.iter().sum::<f64>() / m;
+
+    let b = nf / (m - 1.0) * means.iter().map(|x| (x - grand_mean).powi(2)).sum::<f64>();
+    let w = halves.iter().zip(means.iter())
+        .map(|(h, mean)| h.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (nf - 1.0))
+        .sum::<f64>() / m;
+
+    if w == 0.0 {
+        return if b == 0.0 { 1.0 } else { f64::INFINITY };
+    }
+
+    let var_plus = (nf - 1.0) / nf * w + b / nf;
+    (var_plus / w).sqrt()
+}
+
+/// Rank normalised split R-hat of Vehtari et al. (2021), the maximum of the bulk
+/// R-hat (on normal scores of the ranks) and the tail R-hat (on the folded draws).
+pub fn rank_rhat(chains: &[Vec<f64>]) -> f64 {
+    let bulk = split_rhat(&diagnostics::rank_normalise(chains));
+
+    let all: Vec<f64> = chains.iter().flatten().cloned().collect();
+    let median = diagnostics::quantile(&all, 0.5);
+    let folded: Vec<Vec<f64>> = chains.iter().map(|c| c.iter().map(|x| (x - median).abs()).collect()).collect();
+    let tail = split_rhat(&diagnostics::rank_normalise(&folded));
+
+    bulk.max(tail)
+}
+
+impl Output {
+    /// All chains pooled together.
+    pub fn pooled(&self) -> Vec<mh::Parameters> {
+        self.chains.iter().flatten().cloned().collect()
+    }
+
+    /// Names of the parameters, see [`data::parameter_names`].
+    pub fn names(&self) -> Vec<String> {
+        data::parameter_names(self.starts.first().map_or(0, |q| data::n_traits_of(q.len())))
+    }
+
+    /// Mean and 5-95% interval of the pooled draws, with the multi-chain ESS, MCSE and both R-hats.
+    pub fn summary(&self) -> String {
+        let names = self.names();
+        traces(&self.chains).iter().enumerate()
+            .map(|(d, chains)| {
+                let all: Vec<f64> = chains.iter().flatten().cloned().collect();
+                let mean = all.iter().sum::<f64>() / all.len() as f64;
+                format!("{}: {:.3} [{:.3}, {:.3}] {} split-R-hat: {:.3} rank-R-hat: {:.3}",
+                    names[d], mean, diagnostics::quantile(&all, 0.05), diagnostics::quantile(&all, 0.95),
+                    diagnostics::summary_columns(chains), self.split_rhat[d], self.rank_rhat[d])
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// A warning for every parameter with an R-hat above 1.01.
+    pub fn warnings(&self) -> Vec<String> {
+        self.names().iter().zip(self.split_rhat.iter().zip(self.rank_rhat.iter()))
+            .filter(|(_, (sr, rr))| sr.max(**rr) > RHAT_THRESHOLD)
+            .map(|(name, (sr, rr))| format!("warning: R-hat of {} is {:.3} > {}, the chains have not mixed", name, sr.max(*rr), RHAT_THRESHOLD))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the halves [1, 2], [3, 4], [5, 6], [7, 8] have B = 40/3 and W = 1/2,
+    // so var+ = W/2 + B/2 and R-hat = sqrt(var+/W) = sqrt(83/6)
+    #[test]
+    fn split_rhat_matches_a_hand_computed_value() {
+        let rhat = split_rhat(&[vec![1.0, 2.0, 3.0, 4.0], vec![5.0, 6.0, 7.0, 8.0]]);
+        assert!((rhat - (83.0f64 / 6.0).sqrt()).abs() < 1e-12, "{}", rhat);
+
+        // halves with equal means, and an odd draw left out of the split
+        let rhat = split_rhat(&[vec![1.0, 2.0, 1.0, 2.0, 9.0], vec![2.0, 1.0, 2.0, 1.0]]);
+        assert!((rhat - 0.5f64.sqrt()).abs() < 1e-12, "{}", rhat);
+
+        assert_eq!(split_rhat(&[vec![3.0; 4], vec![3.0; 4]]), 1.0);
+        assert_eq!(split_rhat(&[vec![3.0; 4], vec![4.0; 4]]), f64::INFINITY);
+    }
+}
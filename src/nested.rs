@@ -0,0 +1,184 @@
+//! Nested sampling (Skilling 2006) of the evidence.
+//!
+//! The importance proposal q, the distribution of [`crate::importance::generate_sample`], plays the
+//! prior and L = p/q the likelihood, so that Z is the normalising constant of
+//! p = exp([`crate::mh::log_posterior`]), as estimated by [`crate::smc`] and [`crate::ais`].
+//! Dead points are replaced by a random walk on q constrained to a higher likelihood.
+
+use crate::data::Data;
+use crate::mh;
+use crate::smc::{self, Particle, Target};
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::Normal;
+
+// stop once the live points can add at most this fraction to the evidence
+static TOLERANCE: f64 = 1e-3;
+
+/// The evidence and the weighted dead points.
+pub struct Output {
+    pub log_evidence: f64,
+    /// `sqrt(H/n_live)`, the standard error of the log evidence.
+    pub log_evidence_error: f64,
+    /// The information H, in nats.
+    pub information: f64,
+    pub samples: Vec<mh::Parameters>,
+    /// Posterior weights of the samples, summing to one.
+    pub weights: Vec<f64>,
+    pub n_iter: usize,
+}
+
+/// Nested sampling of the posterior with `n_live` live points, at least two; `n_steps` is the
+/// number of constrained random walk steps used to replace each dead point.
+pub fn run(data: Data, n_live: usize, n_steps: usize, seed: u64) -> Output {
+    run_with(&smc::posterior(&data), n_live, n_steps, seed)
+}
+
+/// Like [`run`], with the proposal of `target` as the prior and p/q as the likelihood.
+pub fn run_with<F: Fn(&[f64]) -> f64>(target: &Target<F>, n_live: usize, n_steps: usize, seed: u64) -> Output {
+    assert!(n_live >= 2, "nested sampling needs at least two live points");
+
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut live: Vec<Particle> = (0..n_live).map(|_| target.sample(&mut rng)).collect();
+
+    let mut dead: Vec<(Vec<f64>, f64)> = Vec::new();
+    let mut log_z = f64::NEG_INFINITY;
+    let mut h = 0.0;
+    let mut scale = 1.0;
+
+    // log of the width of the first shell, X_0 - X_1 = 1 - exp(-1/n)
+    let mut log_width = (1.0 - (-1.0 / n_live as f64).exp()).ln();
+    let mut n_iter = 0;
+
+    loop {
+        // the remaining prior mass is X_i = exp(-i/n); stop before the worst point
+        // becomes dead, so that it is counted once, with the other live points below
+        let log_x = -(n_iter as f64) / n_live as f64;
+        let max_log_l = live.iter().map(|p| p.log_l()).fold(f64::NEG_INFINITY, f64::max);
+
+        if max_log_l + log_x < log_z + TOLERANCE.ln() {
+            break;
+        }
+
+        let (worst, log_l_star) = live.iter().enumerate()
+            .map(|(i, p)| (i, p.log_l()))
+            .fold((0, f64::INFINITY), |a, b| if b.1 < a.1 { b } else { a });
+
+        // accumulate the evidence and the information (Skilling 2006)
+        let log_wt = log_width + log_l_star;
+        let log_z_new = smc::log_sum_exp(&[log_z, log_wt]);
+        h = (log_wt - log_z_new).exp() * log_l_star
+            + if log_z > f64::NEG_INFINITY { (log_z - log_z_new).exp() * (h + log_z) } else { 0.0 }
+            - log_z_new;
+        log_z = log_z_new;
+
+        dead.push((live[worst].q.clone(), log_wt));
+        n_iter += 1;
+
+        // replace the worst point with a copy of another live point, evolved under the constraint L > L*
+        let mut start = rng.gen_range(0..n_live - 1);
+        if start >= worst {
+            start += 1;
+        }
+        let new_point = constrained_walk(&mut rng, target, &live, start, log_l_star, n_steps, &mut scale);
+        live[worst] = new_point;
+
+        log_width -= 1.0 / n_live as f64;
+    }
+
+    // the remaining live points share the final prior mass X = exp(-n_iter/n)
+    let log_x = -(n_iter as f64) / n_live as f64;
+    for p in live.iter() {
+        let log_wt = log_x - (n_live as f64).ln() + p.log_l();
+        let log_z_new = smc::log_sum_exp(&[log_z, log_wt]);
+        h = (log_wt - log_z_new).exp() * p.log_l() + (log_z - log_z_new).exp() * (h + log_z) - log_z_new;
+        log_z = log_z_new;
+        dead.push((p.q.clone(), log_wt));
+    }
+
+    let weights: Vec<f64> = dead.iter().map(|(_, lw)| (lw - log_z).exp()).collect();
+
+    Output {
+        log_evidence: log_z,
+        log_evidence_error: (h / n_live as f64).sqrt(),
+        information: h,
+        samples: dead.iter().map(|(q, _)| mh::Parameters::from_slice(q)).collect(),
+        weights,
+        n_iter,
+    }
+}
+
+// random walk MH on the prior q restricted to L > log_l_star, with the step
+// size scaled to the spread of the live points and adapted to accept about half of the moves
+fn constrained_walk<F: Fn(&[f64]) -> f64>(rng: &mut StdRng, target: &Target<F>, live: &[Particle], start: usize, log_l_star: f64, n_steps: usize, scale: &mut f64) -> Particle {
+    let n = live.len() as f64;
+    let sd: Vec<f64> = (0..live[0].q.len()).map(|d| {
+        let mean = live.iter().map(|p| p.q[d]).sum::<f64>() / n;
+        let var = live.iter().map(|p| (p.q[d] - mean).powi(2)).sum::<f64>() / n;
+        var.sqrt().max(1e-8)
+    }).collect();
+
+    let mut current = live[start].clone();
+    let mut accepted = 0;
+    let mut rejected = 0;
+
+    for _ in 0..n_steps {
+        let new_q: Vec<f64> = current.q.iter().zip(sd.iter())
+            .map(|(x, s)| Normal::new(*x, *scale * s).unwrap().sample(rng))
+            .collect();
+        let proposal = target.particle(new_q);
+
+        let log_ratio = proposal.log_q - current.log_q;
+        if proposal.log_l() > log_l_star && (log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln()) {
+            current = proposal;
+            accepted += 1;
+        } else {
+            rejected += 1;
+        }
+    }
+
+    if accepted > rejected {
+        *scale *= (1.0 / accepted as f64).exp();
+    } else if rejected > accepted {
+        *scale /= (1.0 / rejected as f64).exp();
+    }
+
+    current
+}
+
+impl Output {
+    /// `n` equally weighted posterior draws, obtained by resampling the weighted dead points.
+    pub fn posterior_samples(&self, n: usize, seed: u64) -> Vec<mh::Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut cum = Vec::with_capacity(self.weights.len());
+        let mut total = 0.0;
+        for w in self.weights.iter() {
+            total += w;
+            cum.push(total);
+        }
+
+        (0..n)
+            .map(|_| {
+                let u = rng.gen::<f64>() * total;
+                let i = cum.partition_point(|c| *c < u).min(self.samples.len() - 1);
+                self.samples[i].clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smc::tests::{toy_target, LOG_Z};
+
+    #[test]
+    fn recovers_the_evidence_of_a_gaussian_target() {
+        let out = run_with(&toy_target(), 400, 20, 1);
+        let err = (out.log_evidence - LOG_Z).abs();
+        assert!(err < 0.2 && err < 3.0 * out.log_evidence_error, "log evidence {} +- {}", out.log_evidence, out.log_evidence_error);
+        assert!((out.weights.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,497 @@
+use mcmc_project::config::Config;
+use mcmc_project::data::{self, Data};
+use mcmc_project::diagnostics::Draw;
+use mcmc_project::{advi, ais, bridge, checkpoint, demc, diagnostics, em, ensemble, gibbs, hmc, importance, laplace, map, metadata, mh, multichain, nested, sink, smc, stan, tempering, trace};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use std::path::Path;
+
+// the runs behind the subcommands of the binary, printing their results and writing
+// the samples to config.output
+
+// a single sampler, either one chain from the posterior mode (or the configured start) or several from dispersed starting points
+pub fn sampler(sampler: multichain::Sampler, config: &Config) -> Result<()> {
+    println!("loading data...");
+
+    let data = data::load_from_path(&config.data, &config.format)?;
+    let n_traits = data::n_traits(&data);
+    config.validate_traits(n_traits)?;
+
+    let name = format!("{:?}", sampler).to_lowercase();
+
+    let stan = config.output_format == sink::Format::Stan;
+    if stan && (config.n_chains() >= 2 || config.checkpoint.is_some()) {
+        bail!("Stan CSV is only written for single chains without a checkpoint");
+    }
+    if config.metadata && config.checkpoint.is_some() {
+        bail!("the metadata is not written for checkpointed chains");
+    }
+
+    if config.n_chains() >= 2 {
+        if config.checkpoint.is_some() {
+            bail!("checkpoints are only written for single chains");
+        }
+
+        return print_multichain(&data, sampler, config);
+    }
+
+    let start = match config.start(sampler) {
+        Some(q) => q,
+        None => {
+            println!("finding the posterior mode with L-BFGS...");
+            map::run(&data, &mh::default_start(n_traits)).mode
+        }
+    };
+
+    println!("running {:?}...", sampler);
+
+    let filename = output_file(&config.output, &format!("{}_samples.{}", name, config.output_format.extension()));
+    println!("streaming the samples to file '{}'...", filename);
+
+    if let Some(path) = &config.checkpoint {
+        println!("writing a checkpoint to file '{}' every {} iterations...", path, config.checkpoint_every);
+        let output = checkpoint::Output {
+            path: filename,
+            format: config.output_format,
+            header: trace_header(&data::parameter_names(n_traits), sampler, &[(1, config.seed)], config)?,
+            thin: config.thin,
+            len: 0,
+        };
+        checkpointed_chain(data, sampler, &start, config, path, output)?;
+        return report_checkpointed(sampler, path, config);
+    }
+
+    let columns = match config.metadata {
+        true => metadata::columns(sampler, n_traits),
+        false => data::parameter_names(n_traits),
+    };
+    let mut file_sink = match stan {
+        true => stan::create(&filename, sampler, n_traits, config)?,
+        false => sink::create_with(&filename, config.output_format, &trace_header(&columns, sampler, &[(1, config.seed)], config)?, config.flush_every)?,
+    };
+    let settings = config.chain_settings();
+    let stream = move |sink: &mut dyn sink::Sink| match (stan, config.metadata) {
+        (true, _) => stan::stream_chain(data, sampler, &start, &settings, config.seed, sink),
+        (false, true) => metadata::stream_chain(data, sampler, &start, &settings, config.seed, 1, sink),
+        (false, false) => multichain::stream_chain(data, sampler, &start, &settings, config.seed, sink),
+    };
+
+    if !config.summary {
+        return stream(&mut file_sink);
+    }
+
+    let mut memory = sink::Memory::default();
+    stream(&mut sink::Tee { first: &mut file_sink, second: &mut memory })?;
+
+    report(sampler, &parameters(&memory, n_traits));
+
+    Ok(())
+}
+
+// continues the single chain saved in config.checkpoint
+pub fn resume(config: &Config) -> Result<()> {
+    let Some(path) = &config.checkpoint else {
+        bail!("resuming needs a checkpoint file");
+    };
+
+    let sampler = match checkpoint::sampler_name(path)?.as_str() {
+        "mh" => multichain::Sampler::MH,
+        "hmc" => multichain::Sampler::HMC,
+        "gibbs" => multichain::Sampler::Gibbs,
+        other => bail!("unknown sampler '{}' in the checkpoint '{}'", other, path),
+    };
+
+    println!("loading data...");
+
+    let data = data::load_from_path(&config.data, &config.format)?;
+
+    // the starting point and the tuning are overwritten by the checkpoint
+    let start = mh::default_start(data::n_traits(&data));
+    let every = config.checkpoint_every;
+    let (iteration, total) = checkpoint::progress(path)?;
+    println!("resuming {} at iteration {} of {}...", format!("{:?}", sampler).to_lowercase(), iteration, total);
+    println!("appending the samples to file '{}'...", checkpoint::output(path)?.path);
+    match sampler {
+        multichain::Sampler::MH => checkpoint::resume(&mut mh::Chain::new(data), path, every)?,
+        multichain::Sampler::HMC => checkpoint::resume(&mut hmc::Chain::with_start(data, &start), path, every)?,
+        multichain::Sampler::Gibbs => checkpoint::resume(&mut gibbs::Chain::with_start(data, &start), path, every)?,
+    };
+
+    report_checkpointed(sampler, path, config)
+}
+
+// the same draws as `multichain::stream_chain`, written to `output` and checkpointed to `path`
+fn checkpointed_chain(data: Data, sampler: multichain::Sampler, start: &[f64], config: &Config, path: &str, output: checkpoint::Output) -> Result<()> {
+    let (n_burnin, n_samples, seed, every) = (config.burnin, config.samples, config.seed, config.checkpoint_every);
+    match sampler {
+        multichain::Sampler::MH => checkpoint::run(&mut mh::Chain::with_tuning(data, start, config.mh_tuning()), n_burnin, n_samples, seed, path, every, output),
+        multichain::Sampler::HMC => checkpoint::run(&mut hmc::Chain::with_tuning(data, start, config.hmc_tuning()), n_burnin, n_samples, seed, path, every, output),
+        multichain::Sampler::Gibbs => checkpoint::run(&mut gibbs::Chain::with_start(data, start), n_burnin, n_samples, seed, path, every, output),
+    }
+}
+
+// the summaries of a finished checkpointed chain, from the draws read back from its output
+fn report_checkpointed(sampler: multichain::Sampler, path: &str, config: &Config) -> Result<()> {
+    if !config.summary {
+        return Ok(());
+    }
+    let output = checkpoint::output(path)?;
+    let draws = sink::load(&output.path, output.format, &output.header.columns)?;
+    report(sampler, &draws.iter().map(|v| mh::Parameters::from_slice(v)).collect::<Vec<_>>());
+    Ok(())
+}
+
+fn report(sampler: multichain::Sampler, samples: &[mh::Parameters]) {
+    println!("{:?} results:", sampler);
+
+    println!("{}", mh::Parameters::summary(samples));
+
+    println!("mixing:\n{}", diagnostics::mixing_summary(samples));
+
+    println!("stationarity:\n{}", diagnostics::stationarity_summary(samples));
+    print_burnin(diagnostics::suggested_burnin(samples));
+}
+
+// writes `samples` to `<name>.<extension>` in config.output, in the configured format
+fn save<P: Draw>(samples: &[P], name: &str, seed: u64, config: &Config) -> Result<()> {
+    let filename = output_file(&config.output, &format!("{}.{}", name, config.output_format.extension()));
+
+    println!("saving the samples to file '{}'...", filename);
+
+    let header = trace::Header {
+        sampler: Some(name.trim_end_matches("_samples").to_string()),
+        seeds: [(1, seed)].into(),
+        config: Some(serde_json::to_value(config)?),
+        ..trace::Header::new(&diagnostics::names(samples))
+    };
+    sink::save_with(samples, &header, &filename, config.output_format)
+}
+
+// the header of a binary trace of `sampler` with the given (chain, seed) pairs
+fn trace_header(columns: &[String], sampler: multichain::Sampler, seeds: &[(u32, u64)], config: &Config) -> Result<trace::Header> {
+    Ok(trace::Header {
+        sampler: Some(format!("{:?}", sampler).to_lowercase()),
+        seeds: seeds.iter().copied().collect(),
+        config: Some(serde_json::to_value(config)?),
+        ..trace::Header::new(columns)
+    })
+}
+
+// a binary trace to CSV, or CSV files to a binary trace with one chain per file, by the extension of `output`
+pub fn convert(inputs: &[String], output: &str, selection: &trace::Selection) -> Result<()> {
+    if output.to_lowercase().ends_with(".csv") {
+        let [input] = inputs else {
+            bail!("a CSV file is written from a single trace, got {} inputs", inputs.len());
+        };
+        println!("converting the trace '{}' to file '{}'...", input, output);
+        return trace::to_csv(input, output, selection);
+    }
+
+    if selection.chains.is_some() || selection.iterations.is_some() || selection.columns.is_some() {
+        bail!("only the draws of a trace can be selected, not those of CSV files");
+    }
+    println!("converting {} CSV file(s) to the trace '{}'...", inputs.len(), output);
+    trace::from_csv(inputs, output)
+}
+
+pub fn importance(config: &Config) -> Result<()> {
+    println!("loading data...");
+
+    let data = data::load_from_path(&config.data, &config.format)?;
+
+    println!("russing importance sampling...");
+
+    config.validate_traits(data::n_traits(&data))?;
+
+    let importance_samples = importance::run_with_proposal(data.clone(), &config.proposal(&data), config.importance.samples, config.seed as usize);
+
+    println!("importance sampling results:");
+
+    importance_samples.print_values();
+
+    Ok(())
+}
+
+pub fn all(config: &Config) -> Result<()> {
+    if config.output_format == sink::Format::Stan || config.metadata {
+        bail!("Stan CSV and the metadata are only written by the mh, hmc and gibbs subcommands");
+    }
+
+    let seed = config.seed;
+
+    println!("loading data...");
+
+    let data = data::load_from_path(&config.data, &config.format)?;
+    let n_traits = data::n_traits(&data);
+    config.validate_traits(n_traits)?;
+    let names = data::parameter_names(n_traits).join(", ");
+
+    println!("fitting the mixing model by maximum likelihood...");
+
+    let em_output = em::run(&data);
+
+    println!("maximum likelihood estimates after {} iterations (converged: {}):", em_output.n_iter, em_output.converged);
+
+    println!("{}", em_output.summary());
+
+    println!("log likelihood: {:.3}", em_output.log_likelihood);
+
+    println!("finding the posterior mode with L-BFGS...");
+
+    let map_output = map::run(&data, &mh::default_start(n_traits));
+
+    println!("MAP estimate ({}): {:.3?} after {} iterations (converged: {})", names, map_output.mode, map_output.trace.len() - 1, map_output.converged);
+
+    let start = map_output.mode.clone();
+
+    println!("computing the Laplace approximation...");
+
+    let laplace_output = laplace::run(&data, &map_output)?;
+
+    println!("Laplace approximation results:");
+
+    println!("{}", mh::Parameters::summary(&laplace_output.sample(8000, seed)));
+
+    println!("Laplace unnormalised log evidence: {:.3}", laplace_output.log_evidence);
+
+    for family in [advi::Family::MeanField, advi::Family::FullRank] {
+        println!("running {:?} ADVI...", family);
+
+        let advi_output = advi::run(&data, family, &map_output.mode_unconstrained, 10, 20000, seed);
+
+        println!("{:?} ADVI results:", family);
+
+        println!("{}", mh::Parameters::summary(&advi_output.sample(8000, seed)));
+
+        println!("final ELBO: {:.3} after {} iterations (converged: {})", advi_output.elbo_trace.last().map_or(f64::NAN, |e| e.1), advi_output.n_iter, advi_output.converged);
+    }
+
+    println!("running Metropolis-Hastings...");
+
+    let mut mh_chain = mh::Chain::with_tuning(data.clone(), &config.mh.start.clone().unwrap_or_else(|| start.clone()), config.mh_tuning());
+
+    let mh_samples = diagnostics::thin(&mh_chain.run(config.burnin, config.samples, seed), config.thin);
+
+    println!("MH results:");
+
+    println!("{}", mh::Parameters::summary(&mh_samples));
+
+    save(&mh_samples, "mh_samples", seed, config)?;
+
+    println!("running Hamiltonian Monte Carlo...");
+
+    let mut hmc_chain = hmc::Chain::with_tuning(data.clone(), &config.hmc.start.clone().unwrap_or_else(|| start.clone()), config.hmc_tuning());
+
+    let hmc_samples = diagnostics::thin(&hmc_chain.run(config.burnin, config.samples, seed), config.thin);
+
+    println!("HMC results:");
+
+    println!("{}", hmc::Parameters::summary(&hmc_samples));
+
+    save(&hmc_samples, "hmc_samples", seed, config)?;
+
+    println!("mixing of the single-chain samplers:");
+
+    println!("MH:\n{}", diagnostics::mixing_summary(&mh_samples));
+
+    println!("HMC:\n{}", diagnostics::mixing_summary(&hmc_samples));
+
+    println!("stationarity of the single-chain samplers:");
+
+    println!("MH:\n{}", diagnostics::stationarity_summary(&mh_samples));
+    print_burnin(diagnostics::suggested_burnin(&mh_samples));
+
+    println!("HMC:\n{}", diagnostics::stationarity_summary(&hmc_samples));
+    print_burnin(diagnostics::suggested_burnin(&hmc_samples));
+
+    println!("russing importance sampling...");
+
+    let importance_samples = importance::run_with_proposal(data.clone(), &config.proposal(&data), config.importance.samples, seed as usize);
+
+    println!("importance sampling results:");
+
+    importance_samples.print_values();
+
+    println!("running the Gibbs sampler...");
+
+    let mut gibbs_chain = gibbs::Chain::with_start(data.clone(), &config.gibbs.start.clone().unwrap_or_else(|| start.clone()));
+
+    let gibbs_samples = diagnostics::thin(&gibbs_chain.run(config.burnin, config.samples, seed), config.thin);
+
+    println!("Gibbs sampler results:");
+
+    println!("{}", gibbs::Parameters::summary(&gibbs_samples));
+
+    save(&gibbs_samples, "gibbs_samples", seed, config)?;
+
+    println!("Gibbs mixing:\n{}", diagnostics::mixing_summary(&gibbs_samples));
+
+    println!("Gibbs stationarity:\n{}", diagnostics::stationarity_summary(&gibbs_samples));
+    print_burnin(diagnostics::suggested_burnin(&gibbs_samples));
+
+    println!("running the affine-invariant ensemble sampler...");
+
+    let mut ensemble_chain = ensemble::Chain::new(data.clone(), 32);
+
+    let ensemble_samples = ensemble_chain.run(1000, 1000, seed);
+
+    println!("ensemble sampler results:");
+
+    println!("{}", diagnostics::summary_chains(&diagnostics::deinterleave(&ensemble_samples, ensemble_chain.n_walkers())));
+
+    let rates: Vec<String> = ensemble_chain.acceptance_rates().iter().map(|a| format!("{:.2}", a)).collect();
+
+    println!("acceptance rate per walker: {}", rates.join(" "));
+
+    let taus: Vec<String> = ensemble::autocorrelation_time(&ensemble_samples, ensemble_chain.n_walkers()).iter().map(|t| format!("{:.1}", t)).collect();
+
+    println!("autocorrelation time ({}): {}", names, taus.join(" "));
+
+    save(&ensemble_samples, "ensemble_samples", seed, config)?;
+
+    println!("running differential-evolution MCMC...");
+
+    let mut demc_chain = demc::Chain::new(data.clone(), 24, 0.1);
+
+    let demc_samples = demc_chain.run(1000, 1000, seed);
+
+    println!("DE-MC results:");
+
+    println!("{}", diagnostics::summary_chains(&diagnostics::deinterleave(&demc_samples, demc_chain.n_chains())));
+
+    println!("acceptance rate: DE {:.3}, snooker {:.3}", demc_chain.de_acceptance_rate(), demc_chain.snooker_acceptance_rate());
+
+    save(&demc_samples, "demc_samples", seed, config)?;
+
+    println!("running parallel tempering...");
+
+    let mut pt_chain = tempering::Chain::new(data.clone(), tempering::geometric_ladder(8, 0.01));
+
+    let pt_samples = diagnostics::thin(&pt_chain.run(config.burnin, config.samples, seed), config.thin);
+
+    println!("parallel tempering results (cold chain):");
+
+    println!("{}", mh::Parameters::summary(&pt_samples));
+
+    let rates: Vec<String> = pt_chain.swap_acceptance_rates().iter().zip(pt_chain.betas().windows(2)).map(|(a, b)| format!("{:.3}<->{:.3}: {:.2}", b[0], b[1], a)).collect();
+
+    println!("swap acceptance rates: {}", rates.join(", "));
+
+    save(&pt_samples, "pt_samples", seed, config)?;
+
+    println!("running sequential Monte Carlo...");
+
+    let smc_output = smc::run(data.clone(), 4000, 5, seed);
+
+    println!("SMC results:");
+
+    println!("{}", mh::Parameters::summary(&smc_output.particles));
+
+    println!("tempering steps: {}, resampling steps: {}", smc_output.betas.len() - 1, smc_output.n_resample);
+
+    println!("unnormalised log evidence: {:.3}", smc_output.log_evidence);
+
+    save(&smc_output.particles, "smc_samples", seed, config)?;
+
+    println!("running annealed importance sampling...");
+
+    let ais_output = ais::run(data.clone(), 10, 200, 200, 2, seed);
+
+    println!("AIS unnormalised log evidence: {:.3} (standard error {:.3})", ais_output.log_evidence, ais_output.std_error);
+
+    println!("running nested sampling...");
+
+    let nested_output = nested::run(data.clone(), 500, 30, seed);
+
+    let nested_samples = nested_output.posterior_samples(8000, seed);
+
+    println!("nested sampling results:");
+
+    println!("{}", mh::Parameters::summary(&nested_samples));
+
+    println!("unnormalised log evidence: {:.3} +- {:.3}, information: {:.3} nats, iterations: {}", nested_output.log_evidence, nested_output.log_evidence_error, nested_output.information, nested_output.n_iter);
+
+    save(&nested_samples, "nested_samples", seed, config)?;
+
+    println!("running bridge sampling on the Metropolis-Hastings draws...");
+
+    let bridge_output = bridge::run(&mh_samples, |p| mh::log_posterior(&data, p), seed)?;
+
+    println!("bridge sampling unnormalised log evidence: {:.3} (relative MSE {:.2e}, {} iterations)", bridge_output.log_marginal_likelihood, bridge_output.relative_mse, bridge_output.n_iter);
+
+    if config.n_chains() >= 2 {
+        for sampler in [multichain::Sampler::MH, multichain::Sampler::HMC, multichain::Sampler::Gibbs] {
+            print_multichain(&data, sampler, config)?;
+        }
+    }
+
+    println!("done! :)");
+
+    Ok(())
+}
+
+// every chain is streamed to its own file; its draws are also kept for the summary unless it is turned off
+fn print_multichain(data: &Data, sampler: multichain::Sampler, config: &Config) -> Result<()> {
+    println!("running {} {:?} chains from dispersed starting points...", config.n_chains(), sampler);
+
+    let n_traits = data::n_traits(data);
+    let columns = match config.metadata {
+        true => metadata::columns(sampler, n_traits),
+        false => data::parameter_names(n_traits),
+    };
+    let settings = config.chain_settings();
+    let multi_output = multichain::run_with(data, config.n_chains(), config.seed, |c, start, seed| {
+        let filename = chain_file(sampler, c, config);
+        println!("streaming chain {} to file '{}'...", c + 1, filename);
+
+        let header = trace_header(&columns, sampler, &[(c as u32 + 1, seed)], config)?;
+        let mut file_sink = sink::create_with(&filename, config.output_format, &header, config.flush_every)?;
+        let stream = |sink: &mut dyn sink::Sink| match config.metadata {
+            true => metadata::stream_chain(data.clone(), sampler, start, &settings, seed, c + 1, sink),
+            false => multichain::stream_chain(data.clone(), sampler, start, &settings, seed, sink),
+        };
+
+        if !config.summary {
+            stream(&mut file_sink)?;
+            return Ok(Vec::new());
+        }
+        let mut memory = sink::Memory::default();
+        stream(&mut sink::Tee { first: &mut file_sink, second: &mut memory })?;
+        Ok(parameters(&memory, n_traits))
+    })?;
+
+    if !config.summary {
+        return Ok(());
+    }
+
+    println!("{:?} multi-chain results:", sampler);
+
+    println!("{}", multi_output.summary());
+
+    for warning in multi_output.warnings() {
+        println!("{}", warning);
+    }
+
+    Ok(())
+}
+
+fn chain_file(sampler: multichain::Sampler, c: usize, config: &Config) -> String {
+    output_file(&config.output, &format!("{}_samples_chain{}.{}", format!("{:?}", sampler).to_lowercase(), c + 1, config.output_format.extension()))
+}
+
+// the parameters are the last columns of every format
+fn parameters(memory: &sink::Memory, n_traits: usize) -> Vec<mh::Parameters> {
+    let n = 2 + 2 * n_traits;
+    memory.draws.iter().map(|v| mh::Parameters::from_slice(&v[v.len() - n..])).collect()
+}
+
+pub fn output_file(dir: &str, name: &str) -> String {
+    Path::new(dir).join(name).to_string_lossy().into_owned()
+}
+
+fn print_burnin(burnin: Option<usize>) {
+    match burnin {
+        Some(n) => println!("suggested additional burn-in: {} iterations", n),
+        None => println!("warning: the chain has not reached stationarity, run it for longer"),
+    }
+}
@@ -0,0 +1,308 @@
+//! Writing draws to disk as they are produced.
+//!
+//! A [`Sink`] receives one draw at a time, so a chain streamed with [`stream`] to a file sink
+//! needs constant memory however long it runs. Draws can be written as CSV, JSON Lines or the
+//! binary trace of [`crate::trace`].
+//!
+//! The summaries need every draw: a [`Memory`] sink, e.g. next to a file sink in a [`Tee`],
+//! holds all of them, so memory grows with the length of the chain again. The command-line
+//! tool does this by default and only streams in constant memory with `--no-summary`.
+
+use crate::checkpoint::Resumable;
+use crate::diagnostics::{self, Draw};
+use crate::trace;
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+
+/// A destination for draws.
+pub trait Sink {
+    fn write(&mut self, values: &[f64]) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// Output format of the draws.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One row per draw, with a header of the column names.
+    #[default]
+    Csv,
+    /// One JSON object per line, keyed by the column names.
+    Jsonl,
+    /// The indexed binary trace of [`crate::trace`].
+    Binary,
+    /// The CSV layout of CmdStan, see [`crate::stan`]; only for streamed single chains.
+    Stan,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::Binary => "bin",
+            Format::Stan => "csv",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            "binary" => Ok(Format::Binary),
+            "stan" => Ok(Format::Stan),
+            _ => bail!("unknown output format '{}', expected csv, jsonl, binary or stan", s),
+        }
+    }
+}
+
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+pub struct JsonlSink<W: Write> {
+    writer: W,
+    names: Vec<String>,
+}
+
+/// Flushes the inner sink every `every` draws.
+pub struct Periodic<S: Sink> {
+    inner: S,
+    every: usize,
+    count: usize,
+}
+
+/// Keeps the draws in memory, e.g. for the summaries.
+#[derive(Default)]
+pub struct Memory {
+    pub draws: Vec<Vec<f64>>,
+}
+
+/// Writes every draw to both sinks.
+pub struct Tee<'a> {
+    pub first: &'a mut dyn Sink,
+    pub second: &'a mut dyn Sink,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W, names: &[&str]) -> Result<Self> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(names)?;
+        Ok(Self { writer })
+    }
+
+    /// For a writer that already has the column names.
+    pub fn without_header(writer: W) -> Self {
+        Self { writer: csv::Writer::from_writer(writer) }
+    }
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W, names: &[&str]) -> Self {
+        Self { writer, names: names.iter().map(|n| n.to_string()).collect() }
+    }
+}
+
+impl<S: Sink> Periodic<S> {
+    pub fn new(inner: S, every: usize) -> Self {
+        Self { inner, every: every.max(1), count: 0 }
+    }
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        self.writer.serialize(values)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Sink for JsonlSink<W> {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        let fields: Vec<String> = self.names.iter().zip(values.iter())
+            .map(|(name, v)| Ok(format!("{}:{}", serde_json::to_string(name)?, serde_json::to_string(v)?)))
+            .collect::<Result<Vec<String>>>()?;
+        writeln!(self.writer, "{{{}}}", fields.join(","))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<S: Sink> Sink for Periodic<S> {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        self.inner.write(values)?;
+        self.count += 1;
+        if self.count.is_multiple_of(self.every) {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for Box<dyn Sink> {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        self.as_mut().write(values)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.as_mut().flush()
+    }
+}
+
+impl Sink for Memory {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        self.draws.push(values.to_vec());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for Tee<'_> {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        self.first.write(values)?;
+        self.second.write(values)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+/// A file sink in the given format, flushed every `flush_every` draws.
+pub fn create(path: &str, format: Format, names: &[String], flush_every: usize) -> Result<Box<dyn Sink>> {
+    create_with(path, format, &trace::Header::new(names), flush_every)
+}
+
+/// Like [`create`], with the sampler, the seeds and the configuration in the header of a
+/// binary trace; the other formats only have the column names.
+pub fn create_with(path: &str, format: Format, header: &trace::Header, flush_every: usize) -> Result<Box<dyn Sink>> {
+    if format == Format::Binary {
+        return Ok(Box::new(Periodic::new(trace::Writer::create(path, header)?, flush_every)));
+    }
+    if format == Format::Stan {
+        bail!("Stan CSV is written from the sampler diagnostics of a single chain, see crate::stan::create");
+    }
+    let names: Vec<&str> = header.columns.iter().map(|c| c.as_str()).collect();
+    let file = File::create(path).wrap_err_with(|| format!("cannot create the output file '{}'", path))?;
+    let sink: Box<dyn Sink> = match format {
+        Format::Csv => Box::new(Periodic::new(CsvSink::new(file, &names)?, flush_every)),
+        Format::Jsonl => Box::new(Periodic::new(JsonlSink::new(BufWriter::new(file), &names), flush_every)),
+        Format::Binary | Format::Stan => unreachable!(),
+    };
+    Ok(sink)
+}
+
+/// Reopens a file written by [`create_with`] to append draws to it, after truncating it to
+/// `len` bytes, its length after an earlier flush; whatever was written after that flush is discarded.
+pub fn reopen(path: &str, format: Format, header: &trace::Header, len: u64, flush_every: usize) -> Result<Box<dyn Sink>> {
+    if format == Format::Binary {
+        return Ok(Box::new(Periodic::new(trace::Writer::reopen(path, len)?, flush_every)));
+    }
+    if format == Format::Stan {
+        bail!("Stan CSV is written from the sampler diagnostics of a single chain, see crate::stan::create");
+    }
+    let mut file = OpenOptions::new().write(true).open(path).wrap_err_with(|| format!("cannot open the output file '{}'", path))?;
+    if file.metadata()?.len() < len {
+        bail!("the output file '{}' is shorter than when it was last flushed", path);
+    }
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+
+    let names: Vec<&str> = header.columns.iter().map(|c| c.as_str()).collect();
+    let sink: Box<dyn Sink> = match format {
+        Format::Csv => Box::new(Periodic::new(CsvSink::without_header(file), flush_every)),
+        Format::Jsonl => Box::new(Periodic::new(JsonlSink::new(BufWriter::new(file), &names), flush_every)),
+        Format::Binary | Format::Stan => unreachable!(),
+    };
+    Ok(sink)
+}
+
+/// Reads back the draws of a file written by [`create_with`] with the given columns, e.g. for the summaries.
+pub fn load(path: &str, format: Format, columns: &[String]) -> Result<Vec<Vec<f64>>> {
+    let draws = match format {
+        Format::Csv => {
+            let mut reader = csv::Reader::from_path(path).wrap_err_with(|| format!("cannot open the output file '{}'", path))?;
+            reader.deserialize().collect::<std::result::Result<Vec<Vec<f64>>, csv::Error>>()?
+        }
+        Format::Jsonl => {
+            let file = File::open(path).wrap_err_with(|| format!("cannot open the output file '{}'", path))?;
+            let mut draws = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&line?)?;
+                let row = columns.iter().map(|c| object.get(c).and_then(|v| v.as_f64()).unwrap_or(f64::NAN)).collect();
+                draws.push(row);
+            }
+            draws
+        }
+        Format::Binary => trace::Reader::open(path)?.read(&trace::Selection::default())?,
+        Format::Stan => bail!("Stan CSV is not read back"),
+    };
+    Ok(draws)
+}
+
+/// Writes draws that are already in memory, with the columns of [`crate::data::parameter_names`].
+pub fn save<P: Draw>(draws: &[P], path: &str, format: Format) -> Result<()> {
+    save_with(draws, &trace::Header::new(&diagnostics::names(draws)), path, format)
+}
+
+/// Like [`save`], with the header of [`create_with`].
+pub fn save_with<P: Draw>(draws: &[P], header: &trace::Header, path: &str, format: Format) -> Result<()> {
+    let mut sink = create_with(path, format, header, usize::MAX)?;
+    for p in draws {
+        sink.write(&p.values())?;
+    }
+    sink.flush().wrap_err_with(|| format!("cannot write the output file '{}'", path))
+}
+
+/// The same draws as `Chain::run(n_burnin, n_samples, seed)`, keeping every `thin`-th, written
+/// to `sink` as they are drawn instead of being collected.
+pub fn stream<C>(chain: &mut C, n_burnin: usize, n_samples: usize, thin: usize, seed: u64, sink: &mut dyn Sink) -> Result<()>
+where
+    C: Resumable,
+    C::Draw: Draw,
+{
+    stream_rows(chain, n_burnin, n_samples, thin, seed, sink, |c, _| c.draw().values())
+}
+
+/// Like [`stream`], writing `row(chain, iteration)` after every kept step instead of the draw;
+/// iterations are counted from 1 after the burn-in.
+pub fn stream_rows<C: Resumable>(chain: &mut C, n_burnin: usize, n_samples: usize, thin: usize, seed: u64, sink: &mut dyn Sink, row: impl Fn(&C, usize) -> Vec<f64>) -> Result<()> {
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+    for _ in 0..n_burnin {
+        chain.step(&mut rng);
+    }
+
+    for i in 0..n_samples {
+        chain.step(&mut rng);
+        if i.is_multiple_of(thin.max(1)) {
+            sink.write(&row(chain, i + 1))?;
+        }
+    }
+
+    sink.flush()
+}
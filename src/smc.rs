@@ -0,0 +1,274 @@
+//! Sequential Monte Carlo: an adaptively tempered particle cloud, and its estimate of the evidence.
+//!
+//! The cloud starts from the importance proposal q and moves along
+//! `pi_beta ~ q * (p/q)^beta`, where p is exp([`crate::mh::log_posterior`]). Each step in beta
+//! keeps the conditional ESS of the weights at a fixed fraction of the particles; the cloud is
+//! resampled when its ESS gets low and rejuvenated with random walk MH moves. At beta = 1 the
+//! particles target the posterior, and the log of the normalising constant of p has been
+//! estimated along the way (see `mh::log_posterior` for the constants it leaves out).
+//!
+//! [`run_with`] runs from any proposal to any target, which [`crate::ais`] and [`crate::nested`] share.
+
+use crate::data::Data;
+use crate::{importance, mh};
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::Normal;
+
+// the tempering step is chosen so that the conditional ESS of the new weights is CESS_TARGET * n
+static CESS_TARGET: f64 = 0.9;
+// resample when the ESS drops below RESAMPLE_THRESHOLD * n
+static RESAMPLE_THRESHOLD: f64 = 0.5;
+
+/// The final cloud and the evidence.
+pub struct Output {
+    /// Equally weighted draws from the target.
+    pub particles: Vec<mh::Parameters>,
+    pub log_evidence: f64,
+    /// The tempering schedule, from 0 to 1.
+    pub betas: Vec<f64>,
+    pub n_resample: usize,
+    /// Acceptance rate of the rejuvenation moves after every tempering step.
+    pub acceptance: Vec<f64>,
+}
+
+/// A point `q` with the log densities of the proposal and of the target there.
+#[derive(Clone)]
+pub struct Particle {
+    pub q: Vec<f64>,
+    pub log_q: f64,
+    pub log_p: f64,
+}
+
+/// The proposal q the particles are drawn from and the log of the unnormalised density p they
+/// move to, shared with [`crate::ais`] and [`crate::nested`].
+pub struct Target<F: Fn(&[f64]) -> f64> {
+    pub proposal: importance::Proposal,
+    pub log_p: F,
+}
+
+/// p = exp([`mh::log_posterior`]), from the proposal of [`importance::Proposal::for_data`].
+pub fn posterior(data: &Data) -> Target<impl Fn(&[f64]) -> f64 + '_> {
+    Target {
+        proposal: importance::Proposal::for_data(data),
+        log_p: move |q: &[f64]| mh::log_posterior(data, &mh::Parameters::from_slice(q)),
+    }
+}
+
+impl<F: Fn(&[f64]) -> f64> Target<F> {
+    /// The particle at `q`.
+    pub fn particle(&self, q: Vec<f64>) -> Particle {
+        let log_q = self.proposal.log_density(&importance::Parameters::from_slice(&q));
+        let log_p = (self.log_p)(&q);
+        Particle { q, log_q, log_p }
+    }
+
+    /// A particle drawn from the proposal.
+    pub fn sample(&self, rng: &mut StdRng) -> Particle {
+        self.particle(self.proposal.sample(rng).to_vec())
+    }
+}
+
+impl Particle {
+    /// Log of p/q, the "likelihood" that gets tempered.
+    pub fn log_l(&self) -> f64 {
+        self.log_p - self.log_q
+    }
+
+    fn log_target(&self, beta: f64) -> f64 {
+        self.log_q + beta * self.log_l()
+    }
+}
+
+/// SMC of the posterior with `n_particles` particles; `n_mcmc` is the number of random walk MH moves
+/// used to rejuvenate each particle after every tempering step.
+pub fn run(data: Data, n_particles: usize, n_mcmc: usize, seed: u64) -> Output {
+    run_with(&posterior(&data), n_particles, n_mcmc, seed)
+}
+
+/// Like [`run`], from any proposal to any target.
+pub fn run_with<F: Fn(&[f64]) -> f64>(target: &Target<F>, n_particles: usize, n_mcmc: usize, seed: u64) -> Output {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut particles: Vec<Particle> = (0..n_particles).map(|_| target.sample(&mut rng)).collect();
+    let mut log_w = vec![0.0; n_particles];
+
+    let mut beta = 0.0;
+    let mut log_evidence = 0.0;
+    let mut betas = vec![beta];
+    let mut n_resample = 0;
+    let mut acceptance = Vec::new();
+
+    while beta < 1.0 {
+        let log_l: Vec<f64> = particles.iter().map(|p| p.log_l()).collect();
+        let new_beta = next_beta(&log_w, &log_l, beta);
+
+        // log Z_new - log Z_old = log sum_i W_i * L_i^(new_beta - beta)
+        let w = normalise(&log_w);
+        let incr: Vec<f64> = log_l.iter().zip(w.iter()).map(|(l, wi)| wi.ln() + (new_beta - beta) * l).collect();
+        log_evidence += log_sum_exp(&incr);
+
+        for (lw, l) in log_w.iter_mut().zip(log_l.iter()) {
+            *lw += (new_beta - beta) * l;
+        }
+        beta = new_beta;
+        betas.push(beta);
+
+        if ess(&log_w) < RESAMPLE_THRESHOLD * n_particles as f64 {
+            particles = resample(&mut rng, particles, &log_w);
+            log_w = vec![0.0; n_particles];
+            n_resample += 1;
+        }
+
+        acceptance.push(rejuvenate(&mut rng, target, &mut particles, &log_w, beta, n_mcmc));
+    }
+
+    // make the returned particles equally weighted
+    if log_w.iter().any(|lw| *lw != log_w[0]) {
+        particles = resample(&mut rng, particles, &log_w);
+    }
+
+    Output {
+        particles: particles.iter().map(|p| mh::Parameters::from_slice(&p.q)).collect(),
+        log_evidence,
+        betas,
+        n_resample,
+        acceptance,
+    }
+}
+
+// bisection for the largest beta' <= 1 with CESS(beta') >= CESS_TARGET
+fn next_beta(log_w: &[f64], log_l: &[f64], beta: f64) -> f64 {
+    let w = normalise(log_w);
+    let cess = |b: f64| -> f64 {
+        let d = b - beta;
+        let m = log_l.iter().map(|l| d * l).fold(f64::NEG_INFINITY, f64::max);
+        let (mut num, mut den) = (0.0, 0.0);
+        for (wi, l) in w.iter().zip(log_l.iter()) {
+            let u = (d * l - m).exp();
+            num += wi * u;
+            den += wi * u * u;
+        }
+        num * num / den
+    };
+
+    if cess(1.0) >= CESS_TARGET {
+        return 1.0;
+    }
+
+    let (mut lo, mut hi) = (beta, 1.0);
+    for _ in 0..60 {
+        let mid = 0.5 * (lo + hi);
+        if cess(mid) >= CESS_TARGET {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    // always make some progress
+    lo.max(beta + 1e-10).min(1.0)
+}
+
+/// `n_mcmc` random walk MH moves of every particle targeting `pi_beta`, with the proposal scaled to the
+/// spread of the cloud weighted by `log_w`; returns the acceptance rate.
+pub fn rejuvenate<F: Fn(&[f64]) -> f64>(rng: &mut StdRng, target: &Target<F>, particles: &mut [Particle], log_w: &[f64], beta: f64, n_mcmc: usize) -> f64 {
+    let w = normalise(log_w);
+    let n_dim = particles[0].q.len();
+    let sd: Vec<f64> = (0..n_dim).map(|d| {
+        let mean: f64 = particles.iter().zip(w.iter()).map(|(p, wi)| wi * p.q[d]).sum();
+        let var: f64 = particles.iter().zip(w.iter()).map(|(p, wi)| wi * (p.q[d] - mean).powi(2)).sum();
+        2.38 / (n_dim as f64).sqrt() * var.sqrt().max(1e-8)
+    }).collect();
+
+    let mut accepted = 0;
+    for p in particles.iter_mut() {
+        for _ in 0..n_mcmc {
+            let new_q: Vec<f64> = p.q.iter().zip(sd.iter())
+                .map(|(x, s)| Normal::new(*x, *s).unwrap().sample(rng))
+                .collect();
+            let new_p = target.particle(new_q);
+
+            let log_ratio = new_p.log_target(beta) - p.log_target(beta);
+            if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+                *p = new_p;
+                accepted += 1;
+            }
+        }
+    }
+
+    accepted as f64 / (particles.len() * n_mcmc).max(1) as f64
+}
+
+// systematic resampling
+fn resample(rng: &mut StdRng, particles: Vec<Particle>, log_w: &[f64]) -> Vec<Particle> {
+    let n = particles.len();
+    let w = normalise(log_w);
+    let u0: f64 = rng.gen::<f64>() / n as f64;
+
+    let mut idx = Vec::with_capacity(n);
+    let mut cum = w[0];
+    let mut j = 0;
+    for i in 0..n {
+        let u = u0 + i as f64 / n as f64;
+        while u > cum && j < n - 1 {
+            j += 1;
+            cum += w[j];
+        }
+        idx.push(j);
+    }
+
+    idx.iter()
+        .map(|&j| particles[j].clone())
+        .collect()
+}
+
+fn normalise(log_w: &[f64]) -> Vec<f64> {
+    let m = log_sum_exp(log_w);
+    log_w.iter().map(|lw| (lw - m).exp()).collect()
+}
+
+fn ess(log_w: &[f64]) -> f64 {
+    let w = normalise(log_w);
+    1.0 / w.iter().map(|wi| wi * wi).sum::<f64>()
+}
+
+/// `log(sum(exp(x)))` without overflow, -inf for an empty `x`.
+pub fn log_sum_exp(x: &[f64]) -> f64 {
+    let m = x.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if m == f64::NEG_INFINITY {
+        return m;
+    }
+    m + x.iter().map(|xi| (xi - m).exp()).sum::<f64>().ln()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // the normalising constant of the toy target
+    pub(crate) static LOG_Z: f64 = 3.0;
+
+    // exp(LOG_Z) times a normalised density of the form of the proposal, narrower and off its centre,
+    // so that every estimate of the evidence should come out at LOG_Z
+    pub(crate) fn toy() -> importance::Proposal {
+        importance::Proposal { s_rate: 30.0, mean: vec![0.5, -0.5, 1.0, 0.2], sd: 0.5 }
+    }
+
+    pub(crate) fn toy_target() -> Target<impl Fn(&[f64]) -> f64> {
+        let p = toy();
+        Target {
+            proposal: importance::Proposal { s_rate: importance::S_RATE, mean: vec![0.0; 4], sd: importance::PROPOSAL_SD },
+            log_p: move |q: &[f64]| LOG_Z + p.log_density(&importance::Parameters::from_slice(q)),
+        }
+    }
+
+    #[test]
+    fn recovers_the_evidence_of_a_gaussian_target() {
+        let out = run_with(&toy_target(), 1000, 10, 1);
+        assert!((out.log_evidence - LOG_Z).abs() < 0.1, "log evidence {}", out.log_evidence);
+        assert_eq!(*out.betas.last().unwrap(), 1.0);
+
+        let mean = out.particles.iter().map(|p| p.to_vec()[2]).sum::<f64>() / out.particles.len() as f64;
+        assert!((mean - 0.5).abs() < 0.1, "mean of mu1 {}", mean);
+    }
+}
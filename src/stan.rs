@@ -0,0 +1,148 @@
+//! Draws in the CSV layout of CmdStan.
+//!
+//! A file starts with the configuration of the run as `#` comments, in the nesting of the
+//! CmdStan arguments. The columns are `lp__`, the sampler diagnostics of [`Diagnostics`]
+//! and then the parameters, so that tools reading CmdStan output can read the draws of
+//! `mh`, `hmc` and `gibbs` directly. Only HMC has the `stepsize__`, `treedepth__`,
+//! `n_leapfrog__`, `divergent__` and `energy__` columns; the other two samplers have
+//! `lp__` and `accept_stat__` only, and their own `algorithm` names, `mh` and `gibbs`.
+//!
+//! `lp__` is the log density the samplers target, [`Resumable::log_density`], of the draw:
+//! [`mh::log_posterior`], which is `-hmc::U`, so that `energy__ + lp__` is the kinetic energy
+//! of HMC. It is on the scale of the parameters as they are written, without the Jacobian of
+//! Stan's unconstrained parametrisation.
+
+use crate::checkpoint::Resumable;
+use crate::config::Config;
+use crate::data::Data;
+use crate::diagnostics::Draw;
+use crate::multichain::{Sampler, Settings};
+use crate::sink::{self, CsvSink, Periodic, Sink};
+use crate::{data, gibbs, hmc, mh};
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// A chain that reports the diagnostic columns of Stan CSV after every step.
+pub trait Diagnostics: Resumable {
+    /// Names of the columns between `lp__` and the parameters, starting with `accept_stat__`.
+    const COLUMNS: &'static [&'static str];
+
+    /// Values of [`Diagnostics::COLUMNS`] for the last step.
+    fn diagnostics(&self) -> Vec<f64>;
+}
+
+/// `min(1, ratio)`, or 0 when the ratio is undefined.
+pub fn accept_stat(ratio: f64) -> f64 {
+    if ratio.is_nan() { 0.0 } else { ratio.min(1.0) }
+}
+
+/// All columns of the file written for `sampler` with `n_traits` traits.
+pub fn columns(sampler: Sampler, n_traits: usize) -> Vec<String> {
+    let diagnostics = match sampler {
+        Sampler::MH => <mh::Chain as Diagnostics>::COLUMNS,
+        Sampler::HMC => <hmc::Chain as Diagnostics>::COLUMNS,
+        Sampler::Gibbs => <gibbs::Chain as Diagnostics>::COLUMNS,
+    };
+    let mut columns = vec!["lp__".to_string()];
+    columns.extend(diagnostics.iter().map(|c| c.to_string()));
+    columns.extend(data::parameter_names(n_traits));
+    columns
+}
+
+/// The comment lines before the column names.
+pub fn header(sampler: Sampler, config: &Config, path: &str) -> String {
+    let mut lines = vec![
+        "model = mixing_model".to_string(),
+        format!("sampler = {}", format!("{:?}", sampler).to_lowercase()),
+        "method = sample (Default)".to_string(),
+        "  sample".to_string(),
+        format!("    num_samples = {}", config.samples),
+        format!("    num_warmup = {}", config.burnin),
+        "    save_warmup = 0".to_string(),
+        format!("    thin = {}", config.thin),
+        "    adapt".to_string(),
+        "      engaged = 0".to_string(),
+    ];
+
+    match sampler {
+        Sampler::MH => lines.extend([
+            "    algorithm = mh".to_string(),
+            "      mh".to_string(),
+            format!("        s_proposal_sd = {}", config.mh.s_proposal_sd),
+            format!("        mean_proposal_sd = {}", config.mh.mean_proposal_sd),
+        ]),
+        Sampler::HMC => lines.extend([
+            "    algorithm = hmc".to_string(),
+            "      hmc".to_string(),
+            "        engine = static".to_string(),
+            "          static".to_string(),
+            format!("            int_time = {}", config.hmc.L as f64 * config.hmc.dt),
+            "        metric = diag_e".to_string(),
+            format!("        stepsize = {}", config.hmc.dt),
+            "        stepsize_jitter = 0".to_string(),
+        ]),
+        Sampler::Gibbs => lines.extend([
+            "    algorithm = gibbs".to_string(),
+            "      gibbs".to_string(),
+        ]),
+    }
+
+    lines.extend([
+        "id = 1".to_string(),
+        "data".to_string(),
+        format!("  file = {}", config.data),
+        "random".to_string(),
+        format!("  seed = {}", config.seed),
+        "output".to_string(),
+        format!("  file = {}", path),
+    ]);
+
+    lines.iter().map(|l| format!("# {}\n", l)).collect()
+}
+
+/// A Stan CSV file for a single chain of `sampler` with `n_traits` traits, flushed every `config.flush_every` draws.
+pub fn create(path: &str, sampler: Sampler, n_traits: usize, config: &Config) -> Result<Box<dyn Sink>> {
+    let file = File::create(path).wrap_err_with(|| format!("cannot create the output file '{}'", path))?;
+    let mut writer = BufWriter::new(file);
+
+    write!(writer, "{}", header(sampler, config, path))?;
+    writeln!(writer, "{}", columns(sampler, n_traits).join(","))?;
+
+    // CmdStan writes the step size and the metric after the column names
+    if sampler == Sampler::HMC {
+        writeln!(writer, "# Adaptation terminated")?;
+        writeln!(writer, "# Step size = {}", config.hmc.dt)?;
+        writeln!(writer, "# Diagonal elements of inverse mass matrix:")?;
+        let m = config.hmc.m.clone().unwrap_or_else(|| vec![1.0; 2 + 2 * n_traits]);
+        writeln!(writer, "# {}", m.iter().map(|m| m.to_string()).collect::<Vec<String>>().join(", "))?;
+    }
+
+    Ok(Box::new(Periodic::new(CsvSink::without_header(writer), config.flush_every)))
+}
+
+/// Like [`sink::stream`], writing `lp__` and the diagnostics before every draw.
+pub fn stream<C>(chain: &mut C, n_burnin: usize, n_samples: usize, thin: usize, seed: u64, sink: &mut dyn Sink) -> Result<()>
+where
+    C: Diagnostics,
+    C::Draw: Draw,
+{
+    sink::stream_rows(chain, n_burnin, n_samples, thin, seed, sink, |c, _| {
+        let values = c.draw().values();
+        let mut row = vec![c.log_density()];
+        row.extend(c.diagnostics());
+        row.extend(values);
+        row
+    })
+}
+
+/// The same draws as [`crate::multichain::run_chain`], written to `sink` in the columns of [`columns`].
+pub fn stream_chain(data: Data, sampler: Sampler, start: &[f64], settings: &Settings, seed: u64, sink: &mut dyn Sink) -> Result<()> {
+    let (n_burnin, n_samples, thin) = (settings.n_burnin, settings.n_samples, settings.thin);
+    match sampler {
+        Sampler::MH => stream(&mut mh::Chain::with_tuning(data, start, settings.mh), n_burnin, n_samples, thin, seed, sink),
+        Sampler::HMC => stream(&mut hmc::Chain::with_tuning(data, start, settings.hmc.clone()), n_burnin, n_samples, thin, seed, sink),
+        Sampler::Gibbs => stream(&mut gibbs::Chain::with_start(data, start), n_burnin, n_samples, thin, seed, sink),
+    }
+}
@@ -0,0 +1,184 @@
+//! Parallel tempering: replicas of a random-walk Metropolis chain at decreasing inverse temperatures.
+//!
+//! The replica at inverse temperature beta targets [`crate::mh::log_posterior`] times beta, so
+//! the hot replicas cross between modes easily; neighbouring replicas swap their states, and
+//! only the draws of the cold replica, at beta = 1, are returned.
+
+use crate::data::{self, Data};
+use crate::mh::{self, Parameters};
+use rand::prelude::*;
+use rand::distributions::Distribution;
+use statrs::distribution::{Uniform, Normal};
+
+// proposal sds of the cold chain, hotter replicas scale them by 1/sqrt(beta)
+static SPROPSD: f64 = 0.2;
+static MEANPROPSD: f64 = 0.5;
+
+/// A ladder of replicas, the first one the cold chain.
+pub struct Chain {
+    data: Data,
+    betas: Vec<f64>,
+    replicas: Vec<Vec<f64>>,
+    log_p: Vec<f64>,
+    swap_accepted: Vec<usize>,
+    swap_proposed: Vec<usize>,
+}
+
+/// Inverse temperatures `1 = beta_0 > beta_1 > ... > beta_{n-1} = beta_min`, evenly spaced on the log scale.
+pub fn geometric_ladder(n_replicas: usize, beta_min: f64) -> Vec<f64> {
+    assert!(n_replicas >= 2, "need at least two replicas");
+    assert!(beta_min > 0.0 && beta_min < 1.0, "beta_min must be in (0, 1)");
+
+    (0..n_replicas)
+        .map(|i| beta_min.powf(i as f64 / (n_replicas - 1) as f64))
+        .collect()
+}
+
+impl Chain {
+    /// `betas` are the inverse temperatures of the replicas, decreasing; the first one must be the cold chain, beta = 1.
+    pub fn new(data: Data, betas: Vec<f64>) -> Self {
+        assert!(betas.len() >= 2, "need at least two replicas");
+        assert!(betas[0] == 1.0, "the first replica must be the cold chain");
+        assert!(betas.windows(2).all(|b| b[1] < b[0] && b[1] > 0.0), "inverse temperatures must be decreasing and positive");
+
+        // same starting point as `mh::Chain::new` for every replica
+        let start = mh::default_start(data::n_traits(&data));
+        let log_p0 = mh::log_posterior(&data, &Parameters::from_slice(&start));
+        let n = betas.len();
+
+        Self {
+            data,
+            betas,
+            replicas: vec![start; n],
+            log_p: vec![log_p0; n],
+            swap_accepted: vec![0; n - 1],
+            swap_proposed: vec![0; n - 1],
+        }
+    }
+
+    /// Runs `n_burnin` iterations, then returns the next `n_samples` draws of the cold chain.
+    pub fn run(&mut self, n_burnin: usize, n_samples: usize, seed: u64) -> Vec<Parameters> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..n_burnin {
+            self.step(&mut rng);
+        }
+
+        self.swap_accepted.iter_mut().for_each(|a| *a = 0);
+        self.swap_proposed.iter_mut().for_each(|a| *a = 0);
+
+        let mut samples = Vec::with_capacity(n_samples);
+
+        for _ in 0..n_samples {
+            self.step(&mut rng);
+            samples.push(Parameters::from_slice(&self.replicas[0]));
+        }
+
+        samples
+    }
+
+    /// Inverse temperatures of the replicas.
+    pub fn betas(&self) -> &[f64] {
+        &self.betas
+    }
+
+    /// Acceptance rate of the swaps between replicas `i` and `i + 1` since the burn-in.
+    pub fn swap_acceptance_rates(&self) -> Vec<f64> {
+        self.swap_accepted.iter().zip(self.swap_proposed.iter())
+            .map(|(&a, &n)| if n > 0 { a as f64 / n as f64 } else { 0.0 })
+            .collect()
+    }
+
+    fn step(&mut self, rng: &mut StdRng) {
+        for i in 0..self.replicas.len() {
+            self.update_s(rng, i);
+            self.update_tau(rng, i);
+            let d = data::n_traits_of(self.replicas[i].len());
+            self.update_means(rng, i, 2..2 + d);
+            self.update_means(rng, i, 2 + d..2 + 2 * d);
+        }
+        self.swap(rng);
+    }
+
+    // tempered Metropolis acceptance of a new state for replica i
+    fn accept(&mut self, rng: &mut StdRng, i: usize, new_q: Vec<f64>) {
+        let new_log_p = mh::log_posterior(&self.data, &Parameters::from_slice(&new_q));
+        let log_ratio = self.betas[i] * (new_log_p - self.log_p[i]);
+
+        if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+            self.replicas[i] = new_q;
+            self.log_p[i] = new_log_p;
+        }
+    }
+
+    fn update_s(&mut self, rng: &mut StdRng, i: usize) {
+        let sd = SPROPSD / self.betas[i].sqrt();
+        let mut new_q = self.replicas[i].clone();
+        new_q[0] = Normal::new(new_q[0], sd).unwrap().sample(rng);
+        self.accept(rng, i, new_q);
+    }
+
+    fn update_tau(&mut self, rng: &mut StdRng, i: usize) {
+        // independent uniform proposal => correction factor is 1
+        let mut new_q = self.replicas[i].clone();
+        new_q[1] = Uniform::new(0.0, 1.0).unwrap().sample(rng);
+        self.accept(rng, i, new_q);
+    }
+
+    // joint update of the coordinates in `range`, all of mu or all of gamma
+    fn update_means(&mut self, rng: &mut StdRng, i: usize, range: std::ops::Range<usize>) {
+        let sd = MEANPROPSD / self.betas[i].sqrt();
+        let mut new_q = self.replicas[i].clone();
+        for k in range {
+            new_q[k] = Normal::new(new_q[k], sd).unwrap().sample(rng);
+        }
+        self.accept(rng, i, new_q);
+    }
+
+    // propose one swap between a random pair of neighbouring replicas
+    fn swap(&mut self, rng: &mut StdRng) {
+        let i = rng.gen_range(0..self.replicas.len() - 1);
+        let j = i + 1;
+
+        let log_ratio = (self.betas[i] - self.betas[j]) * (self.log_p[j] - self.log_p[i]);
+
+        self.swap_proposed[i] += 1;
+        if log_ratio >= 0.0 || log_ratio > rng.gen::<f64>().ln() {
+            self.replicas.swap(i, j);
+            self.log_p.swap(i, j);
+            self.swap_accepted[i] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Row;
+
+    // group 4 has the mean of group 1, so that the posterior of tau piles up against its bound at 1
+    fn data() -> Data {
+        (0..40).map(|i| {
+            let m = match i % 4 { 0 | 3 => -1.0, 1 => 1.0, _ => 0.0 };
+            Row { group: (i % 4 + 1) as u8, x: vec![m + 0.3 * (i as f64 * 0.7).sin(), -m + 0.3 * (i as f64 * 1.1).cos()] }
+        }).collect()
+    }
+
+    fn inside(q: &[f64]) -> bool {
+        q[0] > 0.0 && q[0] <= 10.0 && q[1] > 0.0 && q[1] < 1.0
+    }
+
+    // the hot replicas take the largest steps, so every replica is checked, not only the cold chain
+    #[test]
+    fn moves_and_swaps_keep_every_replica_inside_the_support() {
+        let mut chain = Chain::new(data(), geometric_ladder(6, 0.01));
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut max_tau: f64 = 0.0;
+        for _ in 0..500 {
+            chain.step(&mut rng);
+            assert!(chain.replicas.iter().all(|q| inside(q)));
+            max_tau = chain.replicas.iter().map(|q| q[1]).fold(max_tau, f64::max);
+        }
+        assert!(chain.swap_accepted.iter().sum::<usize>() > 0);
+        assert!(max_tau > 0.95);
+    }
+}
@@ -0,0 +1,519 @@
+//! A self-describing binary trace with random access.
+//!
+//! The file starts with [`MAGIC`], the length of the header as a u64 and the [`Header`] as
+//! JSON: the column names, the sampler, the seed of every chain and the configuration of
+//! the run. The draws follow in chunks of at most [`CHUNK_ROWS`] rows of one chain, each a
+//! u32 chain id, the u64 position of its first row in the chain and the u32 number of rows,
+//! then the values as little-endian f64, column by column. The file ends with an index of
+//! the chunks and a footer with the offset of the index, the number of chunks and
+//! [`INDEX_MAGIC`].
+//!
+//! The index is rewritten at every flush of the [`Writer`], so the file is complete after
+//! every flush of a streamed chain. A file without a valid footer, e.g. after a crash, is
+//! read by scanning the chunks instead.
+
+use crate::sink::Sink;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// First bytes of a trace file.
+pub static MAGIC: &[u8; 8] = b"MCMCTRC1";
+
+/// Last bytes of a trace file with an index.
+pub static INDEX_MAGIC: &[u8; 8] = b"MCMCIDX1";
+
+/// Maximum number of rows of a chunk.
+pub static CHUNK_ROWS: usize = 4096;
+
+// chain id, first row and number of rows
+static CHUNK_HEADER: u64 = 16;
+// chain id, first row, number of rows and offset
+static INDEX_ENTRY: usize = 24;
+// offset of the index, number of chunks and INDEX_MAGIC
+static FOOTER: u64 = 24;
+
+/// What a trace holds, apart from the draws.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Header {
+    pub columns: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampler: Option<String>,
+    /// Seed of every chain, by chain id.
+    pub seeds: BTreeMap<u32, u64>,
+    /// The resolved configuration of the run, as written to `config.json`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<serde_json::Value>,
+}
+
+impl Header {
+    /// A header with only the column names.
+    pub fn new(columns: &[String]) -> Self {
+        Self { columns: columns.to_vec(), ..Self::default() }
+    }
+}
+
+/// Position of a chunk in the file.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    pub chain: u32,
+    pub first_row: u64,
+    pub n_rows: u32,
+    pub offset: u64,
+}
+
+/// Writes the draws of one or more chains to a trace file.
+pub struct Writer {
+    file: BufWriter<File>,
+    n_columns: usize,
+    chain: u32,
+    // rows of the current chain that are not written yet
+    pending: Vec<Vec<f64>>,
+    // rows written so far of every chain
+    n_rows: BTreeMap<u32, u64>,
+    index: Vec<Chunk>,
+    // end of the last chunk, where the index starts
+    data_end: u64,
+}
+
+impl Writer {
+    /// Creates the file at `path` and writes the header; draws are written to chain 1 until [`Writer::set_chain`].
+    pub fn create(path: &str, header: &Header) -> Result<Self> {
+        let file = File::create(path).wrap_err_with(|| format!("cannot create the trace '{}'", path))?;
+        let mut file = BufWriter::new(file);
+
+        let json = serde_json::to_vec(header)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&(json.len() as u64).to_le_bytes())?;
+        file.write_all(&json)?;
+
+        let data_end = (MAGIC.len() + 8 + json.len()) as u64;
+        Ok(Self { file, n_columns: header.columns.len(), chain: 1, pending: Vec::new(), n_rows: BTreeMap::new(), index: Vec::new(), data_end })
+    }
+
+    /// Opens a trace written by [`Writer::create`] to append to it, after truncating it to `len`
+    /// bytes, its length after an earlier flush; the following draws go to the last chain of the file.
+    pub fn reopen(path: &str, len: u64) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path).wrap_err_with(|| format!("cannot open the trace '{}'", path))?;
+        if file.metadata()?.len() < len {
+            bail!("the trace '{}' is shorter than when it was last flushed", path);
+        }
+        file.set_len(len)?;
+
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("'{}' is not a trace file", path);
+        }
+        let header_len = read_u64(&mut reader)?;
+        if header_len > len {
+            bail!("the header of the trace '{}' is longer than the file", path);
+        }
+        let mut json = vec![0u8; header_len as usize];
+        reader.read_exact(&mut json)?;
+        let header: Header = serde_json::from_slice(&json)?;
+
+        let n_columns = header.columns.len();
+        let Some(index) = footer_index(&mut reader, len, n_columns)? else {
+            bail!("the trace '{}' has no index at the length it was flushed to", path);
+        };
+
+        let mut n_rows = BTreeMap::new();
+        for chunk in &index {
+            *n_rows.entry(chunk.chain).or_insert(0) += chunk.n_rows as u64;
+        }
+        let data_end = index.last().map_or(MAGIC.len() as u64 + 8 + header_len, |c| c.offset + CHUNK_HEADER + (c.n_rows as usize * n_columns * 8) as u64);
+        let chain = index.last().map_or(1, |c| c.chain);
+
+        Ok(Self { file: BufWriter::new(reader.into_inner()), n_columns, chain, pending: Vec::new(), n_rows, index, data_end })
+    }
+
+    /// Writes the following draws to chain `chain`, after the draws it already has.
+    pub fn set_chain(&mut self, chain: u32) -> Result<()> {
+        if chain != self.chain {
+            self.write_pending()?;
+            self.chain = chain;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let first_row = *self.n_rows.get(&self.chain).unwrap_or(&0);
+        let n_rows = self.pending.len();
+
+        self.file.seek(SeekFrom::Start(self.data_end))?;
+        self.file.write_all(&self.chain.to_le_bytes())?;
+        self.file.write_all(&first_row.to_le_bytes())?;
+        self.file.write_all(&(n_rows as u32).to_le_bytes())?;
+        for c in 0..self.n_columns {
+            for row in &self.pending {
+                self.file.write_all(&row[c].to_le_bytes())?;
+            }
+        }
+
+        self.index.push(Chunk { chain: self.chain, first_row, n_rows: n_rows as u32, offset: self.data_end });
+        self.n_rows.insert(self.chain, first_row + n_rows as u64);
+        self.data_end += CHUNK_HEADER + (n_rows * self.n_columns * 8) as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // the index and the footer go after the last chunk and are overwritten by the next one
+    fn write_index(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_end))?;
+        for chunk in &self.index {
+            self.file.write_all(&chunk.chain.to_le_bytes())?;
+            self.file.write_all(&chunk.first_row.to_le_bytes())?;
+            self.file.write_all(&chunk.n_rows.to_le_bytes())?;
+            self.file.write_all(&chunk.offset.to_le_bytes())?;
+        }
+        self.file.write_all(&self.data_end.to_le_bytes())?;
+        self.file.write_all(&(self.index.len() as u64).to_le_bytes())?;
+        self.file.write_all(INDEX_MAGIC)?;
+        self.file.flush()?;
+
+        let end = self.data_end + (self.index.len() * INDEX_ENTRY) as u64 + FOOTER;
+        self.file.get_ref().set_len(end)?;
+        Ok(())
+    }
+}
+
+impl Sink for Writer {
+    fn write(&mut self, values: &[f64]) -> Result<()> {
+        if values.len() != self.n_columns {
+            bail!("a row of the trace has {} columns, got {} values", self.n_columns, values.len());
+        }
+        self.pending.push(values.to_vec());
+        if self.pending.len() >= CHUNK_ROWS {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.write_index()
+    }
+}
+
+/// Which draws to read: all of them for every field that is `None`.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub chains: Option<Vec<u32>>,
+    /// Positions of the draws in their chain, from 0.
+    pub iterations: Option<Range<u64>>,
+    pub columns: Option<Vec<String>>,
+}
+
+/// Reads slices of a trace file.
+pub struct Reader {
+    file: BufReader<File>,
+    header: Header,
+    index: Vec<Chunk>,
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::read_index(path).wrap_err_with(|| format!("invalid trace '{}'", path))
+    }
+
+    fn read_index(path: &str) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path).wrap_err_with(|| format!("cannot open the trace '{}'", path))?);
+
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a trace file");
+        }
+        let header_len = read_u64(&mut file)?;
+        let len = file.get_ref().metadata()?.len();
+        if header_len > len {
+            bail!("the header is longer than the file");
+        }
+        let mut json = vec![0u8; header_len as usize];
+        file.read_exact(&mut json)?;
+        let header: Header = serde_json::from_slice(&json)?;
+
+        let data_start = MAGIC.len() as u64 + 8 + header_len;
+        let index = match footer_index(&mut file, len, header.columns.len())? {
+            Some(index) => index,
+            None => scan(&mut file, data_start, len, header.columns.len())?,
+        };
+
+        Ok(Self { file, header, index })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.index
+    }
+
+    /// Ids of the chains, in increasing order.
+    pub fn chains(&self) -> Vec<u32> {
+        let mut chains: Vec<u32> = self.index.iter().map(|c| c.chain).collect();
+        chains.sort();
+        chains.dedup();
+        chains
+    }
+
+    /// Number of draws of `chain`.
+    pub fn n_rows(&self, chain: u32) -> u64 {
+        self.index.iter().filter(|c| c.chain == chain).map(|c| c.n_rows as u64).sum()
+    }
+
+    /// The selected draws, chain by chain, with the selected columns in the order given.
+    /// Only the chunks and the columns that are needed are read.
+    pub fn read(&mut self, selection: &Selection) -> Result<Vec<Vec<f64>>> {
+        let columns: Vec<usize> = match &selection.columns {
+            Some(names) => names.iter().map(|name| self.column(name)).collect::<Result<_>>()?,
+            None => (0..self.header.columns.len()).collect(),
+        };
+        let chains = selection.chains.clone().unwrap_or_else(|| self.chains());
+        let iterations = selection.iterations.clone().unwrap_or(0..u64::MAX);
+        if iterations.start > iterations.end {
+            bail!("the first draw {} is after the end {} of the selection", iterations.start, iterations.end);
+        }
+
+        let mut rows = Vec::new();
+        for chain in chains {
+            let mut chunks: Vec<Chunk> = self.index.iter()
+                .filter(|c| c.chain == chain && c.first_row < iterations.end && c.first_row + c.n_rows as u64 > iterations.start)
+                .copied()
+                .collect();
+            chunks.sort_by_key(|c| c.first_row);
+
+            for chunk in chunks {
+                let to = (iterations.end - chunk.first_row).min(chunk.n_rows as u64);
+                let from = iterations.start.saturating_sub(chunk.first_row).min(to);
+                let first = rows.len();
+                rows.extend((from..to).map(|_| Vec::with_capacity(columns.len())));
+
+                for &c in &columns {
+                    let start = chunk.offset + CHUNK_HEADER + (c as u64 * chunk.n_rows as u64 + from) * 8;
+                    self.file.seek(SeekFrom::Start(start))?;
+                    let mut bytes = vec![0u8; ((to - from) * 8) as usize];
+                    self.file.read_exact(&mut bytes)?;
+                    for (row, b) in rows[first..].iter_mut().zip(bytes.chunks_exact(8)) {
+                        row.push(f64::from_le_bytes(b.try_into().unwrap()));
+                    }
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    /// All draws of one column of `chain`.
+    pub fn column_of(&mut self, chain: u32, name: &str) -> Result<Vec<f64>> {
+        let selection = Selection { chains: Some(vec![chain]), columns: Some(vec![name.to_string()]), ..Selection::default() };
+        Ok(self.read(&selection)?.into_iter().map(|row| row[0]).collect())
+    }
+
+    fn column(&self, name: &str) -> Result<usize> {
+        self.header.columns.iter().position(|c| c == name)
+            .ok_or_else(|| eyre!("no column '{}', the columns are {:?}", name, self.header.columns))
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// the index of the footer, if the file ends with one that fits the chunks; the offsets and
+// lengths are read from the file, so any overflow means that there is no valid footer
+fn footer_index(file: &mut BufReader<File>, len: u64, n_columns: usize) -> Result<Option<Vec<Chunk>>> {
+    if len < FOOTER {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(len - FOOTER))?;
+    let index_offset = read_u64(file)?;
+    let n_chunks = read_u64(file)?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    let index_end = n_chunks.checked_mul(INDEX_ENTRY as u64)
+        .and_then(|n| n.checked_add(index_offset))
+        .and_then(|n| n.checked_add(FOOTER));
+    if &magic != INDEX_MAGIC || index_end != Some(len) {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(index_offset))?;
+    let mut index = Vec::with_capacity(n_chunks as usize);
+    for _ in 0..n_chunks {
+        let chain = read_u32(file)?;
+        let first_row = read_u64(file)?;
+        let n_rows = read_u32(file)?;
+        let offset = read_u64(file)?;
+        let end = offset.checked_add(CHUNK_HEADER + n_rows as u64 * n_columns as u64 * 8);
+        if end.is_none_or(|end| end > index_offset) {
+            return Ok(None);
+        }
+        index.push(Chunk { chain, first_row, n_rows, offset });
+    }
+    Ok(Some(index))
+}
+
+// the complete chunks from the start of the data, for a file without an index
+fn scan(file: &mut BufReader<File>, mut offset: u64, len: u64, n_columns: usize) -> Result<Vec<Chunk>> {
+    let mut index = Vec::new();
+    while offset + CHUNK_HEADER <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        let chain = read_u32(file)?;
+        let first_row = read_u64(file)?;
+        let n_rows = read_u32(file)?;
+        let end = offset + CHUNK_HEADER + (n_rows as usize * n_columns * 8) as u64;
+        if n_rows == 0 || end > len {
+            break;
+        }
+        index.push(Chunk { chain, first_row, n_rows, offset });
+        offset = end;
+    }
+    Ok(index)
+}
+
+/// Writes the selected draws of a trace as CSV, with the column names as header.
+pub fn to_csv(trace: &str, csv: &str, selection: &Selection) -> Result<()> {
+    let mut reader = Reader::open(trace)?;
+    let names = selection.columns.clone().unwrap_or_else(|| reader.header().columns.clone());
+    let rows = reader.read(selection)?;
+
+    let mut writer = csv::Writer::from_path(csv).wrap_err_with(|| format!("cannot create the output file '{}'", csv))?;
+    writer.write_record(&names)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes CSV files of draws, e.g. `mh_samples.csv`, to a trace with one chain per file,
+/// numbered from 1. Every file must have the same columns.
+pub fn from_csv(csvs: &[String], trace: &str) -> Result<()> {
+    let mut writer: Option<Writer> = None;
+    let mut columns: Vec<String> = Vec::new();
+
+    for (c, path) in csvs.iter().enumerate() {
+        let mut reader = csv::Reader::from_path(path).wrap_err_with(|| format!("cannot open '{}'", path))?;
+        let names: Vec<String> = reader.headers()?.iter().map(|h| h.to_string()).collect();
+
+        let writer = match &mut writer {
+            Some(w) => {
+                if names != columns {
+                    bail!("the columns of '{}' are {:?}, expected {:?}", path, names, columns);
+                }
+                w
+            }
+            None => {
+                columns = names;
+                let header = Header { columns: columns.clone(), ..Header::default() };
+                writer.insert(Writer::create(trace, &header)?)
+            }
+        };
+        writer.set_chain(c as u32 + 1)?;
+
+        for (i, record) in reader.records().enumerate() {
+            let row: Vec<f64> = record?.deserialize(None)
+                .wrap_err_with(|| format!("row {} of '{}' is not a row of numbers", i + 2, path))?;
+            writer.write(&row)?;
+        }
+    }
+
+    match writer {
+        Some(mut w) => w.flush(),
+        None => bail!("no CSV files to convert"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("mcmc_project_trace_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    // more rows than CHUNK_ROWS in the first chain, so that it spans two chunks
+    fn rows(chain: usize, n: usize) -> Vec<Vec<f64>> {
+        (0..n).map(|i| vec![chain as f64, i as f64, (i as f64 * 0.37).sin() / 3.0]).collect()
+    }
+
+    fn write_csv(path: &str, rows: &[Vec<f64>]) {
+        let mut writer = csv::Writer::from_path(path).unwrap();
+        writer.write_record(["chain", "i", "x"]).unwrap();
+        for row in rows {
+            writer.serialize(row).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    fn read_csv(path: &str) -> (Vec<String>, Vec<Vec<f64>>) {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let names = reader.headers().unwrap().iter().map(|h| h.to_string()).collect();
+        let rows = reader.deserialize().map(|r| r.unwrap()).collect();
+        (names, rows)
+    }
+
+    fn trace(name: &str) -> String {
+        let csvs = [file(&format!("{}_1.csv", name)), file(&format!("{}_2.csv", name))];
+        write_csv(&csvs[0], &rows(1, CHUNK_ROWS + 500));
+        write_csv(&csvs[1], &rows(2, 300));
+        let trace = file(&format!("{}.trace", name));
+        from_csv(&csvs, &trace).unwrap();
+        trace
+    }
+
+    #[test]
+    fn csv_files_round_trip_through_a_trace() {
+        let trace = trace("round_trip");
+        let csv = file("all.csv");
+        to_csv(&trace, &csv, &Selection::default()).unwrap();
+
+        let (names, read) = read_csv(&csv);
+        assert_eq!(names, ["chain", "i", "x"]);
+        assert_eq!(read, [rows(1, CHUNK_ROWS + 500), rows(2, 300)].concat());
+    }
+
+    #[test]
+    fn a_selection_reads_only_the_given_chains_columns_and_draws() {
+        let mut reader = Reader::open(&trace("selection")).unwrap();
+        assert_eq!(reader.chains(), [1, 2]);
+        assert_eq!(reader.n_rows(1), CHUNK_ROWS as u64 + 500);
+
+        // across the boundary of the two chunks of the first chain
+        let start = CHUNK_ROWS as u64 - 10;
+        let selection = Selection { chains: Some(vec![1]), iterations: Some(start..start + 20), columns: Some(vec!["x".to_string(), "i".to_string()]) };
+        let expected: Vec<Vec<f64>> = rows(1, CHUNK_ROWS + 500)[start as usize..start as usize + 20].iter().map(|r| vec![r[2], r[1]]).collect();
+        assert_eq!(reader.read(&selection).unwrap(), expected);
+
+        // past the end of the second chain
+        let selection = Selection { chains: Some(vec![2]), iterations: Some(290..1000), ..Selection::default() };
+        assert_eq!(reader.read(&selection).unwrap(), rows(2, 300)[290..].to_vec());
+
+        let selection = Selection { iterations: Some(10..10), ..Selection::default() };
+        assert!(reader.read(&selection).unwrap().is_empty());
+
+        let selection = Selection { iterations: Some(Range { start: 20, end: 10 }), ..Selection::default() };
+        assert!(reader.read(&selection).is_err());
+    }
+}